@@ -0,0 +1,64 @@
+use morpheus::world::components::transform::Transform;
+
+pub use morpheus::*;
+
+/// Check that a csg compiled into its own shader renders the same as the interpreted one.
+/// Both are rendered offscreen, and the frames are compared pixel by pixel.
+fn main() {
+
+    let size = (320, 240);
+
+    let mut renderer = match renderer::Renderer::new_headless(size, renderer::RendererDescriptor::new()) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            println!("Unable to create renderer: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    renderer.load_csg(0, scene()).expect("the tree fits the raymarcher stacks");
+    renderer.load_static_csg(1, scene()).expect("the tree fits the raymarcher stacks");
+
+    let interpreted = renderer.create_obj(Transform::origin().rotated(glam::Quat::from_axis_angle(glam::Vec3::Y, 0.3)), 0);
+    let interpreted_frame = render_frame(&mut renderer);
+
+    renderer.world_mut().set_asset(interpreted, 1);
+    let compiled_frame = render_frame(&mut renderer);
+
+    // the same operations run in the same order, only float differences are expected
+    let differing_pixels = interpreted_frame.chunks(4)
+        .zip(compiled_frame.chunks(4))
+        .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > 8))
+        .count();
+    let max_differing_pixels = (size.0 * size.1) as usize / 200;
+
+    if differing_pixels > max_differing_pixels {
+        println!("Compiled csg differs: {differing_pixels} pixels out of {}", size.0 * size.1);
+        std::process::exit(1);
+    }
+    println!("Compiled csg matches ({differing_pixels} pixels differ)");
+}
+
+fn scene() -> csg::CSG {
+    csg::csg!(
+        csg::BinOp::Inter => {
+            csg::Primitive::sphere(0.3)
+        } {
+            csg::Primitive::sphere(0.3).at(glam::Vec3::new(0.0, 0.2, 0.0))
+        }
+    )
+}
+
+fn render_frame(renderer: &mut renderer::Renderer) -> Vec<u8> {
+    if let Err(e) = renderer.render() {
+        println!("Unable to render: {e:?}");
+        std::process::exit(1);
+    }
+    match renderer.read_frame() {
+        Ok(pixels) => pixels,
+        Err(e) => {
+            println!("Unable to read back the frame: {e:?}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use morpheus::world::components::transform::Transform;
+
+pub use morpheus::*;
+
+/// Spawns the objects of a scene, and returns them so they can be despawned afterwards.
+type SpawnScene = fn(&mut renderer::Renderer) -> Vec<world::entity::Entity>;
+
+/// Measure the time of each pass with and without the cone marching prepass on a few standard scenes,
+/// on the fallback (software) adapter so the numbers can be compared between machines.
+/// Passes are timed with timestamp queries when the adapter supports them,
+/// otherwise the whole frame is timed on the cpu, including the read back which is the same for both.
+/// Also compares the frames with and without the prepass, pixel for pixel.
+fn main() {
+
+    let size = (1280, 720);
+    let frames = 10;
+
+    let descriptor = renderer::RendererDescriptor::new().force_fallback_adapter(true);
+    let mut renderer = match renderer::Renderer::new_headless(size, descriptor.clone().features(wgpu::Features::TIMESTAMP_QUERY)) {
+        Ok(renderer) => renderer,
+        Err(error::MorpheusError::UnsupportedFeatures(_)) => {
+            println!("The adapter has no timestamp queries, timing whole frames on the cpu instead.");
+            match renderer::Renderer::new_headless(size, descriptor) {
+                Ok(renderer) => renderer,
+                Err(e) => {
+                    println!("Unable to create renderer: {e:?}");
+                    std::process::exit(1);
+                }
+            }
+        },
+        Err(e) => {
+            println!("Unable to create renderer: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    // the scene of the headless example
+    renderer.load_csg(
+        0,
+        csg::csg!(
+            csg::BinOp::Inter => {
+                csg::Primitive::sphere(0.3)
+            } {
+                csg::Primitive::sphere(0.3).at(glam::Vec3::new(0.0, 0.2, 0.0))
+            }
+        )
+    ).expect("the tree fits the raymarcher stacks");
+    // small blobs, for the grid
+    renderer.load_csg(
+        1,
+        csg::csg!(
+            csg::BinOp::Union => {
+                csg::Primitive::sphere(0.08)
+            } {
+                csg::Primitive::sphere(0.06).at(glam::Vec3::new(0.05, 0.08, 0.0))
+            }
+        )
+    ).expect("the tree fits the raymarcher stacks");
+    // spheres on both sides of the camera, its bounding box contains the camera
+    renderer.load_csg(
+        2,
+        csg::csg!(
+            csg::BinOp::Union => {
+                csg::Primitive::sphere(0.3).at(glam::Vec3::new(-0.5, -0.2, -0.8))
+            } {
+                csg::Primitive::sphere(0.2).at(glam::Vec3::new(0.6, 0.1, 0.4))
+            }
+        )
+    ).expect("the tree fits the raymarcher stacks");
+
+    let scenes: [(&str, SpawnScene); 4] = [
+        ("headless scene", |renderer| vec![
+            renderer.create_obj(Transform::origin().rotated(glam::Quat::from_axis_angle(glam::Vec3::Y, 0.3)), 0),
+        ]),
+        ("object grid", |renderer| {
+            let mut entities = Vec::new();
+            for x in -4..=4 {
+                for z in -4..=2 {
+                    let position = glam::Vec3::new(x as f32 * 0.3, 0.0, z as f32 * 0.3);
+                    entities.push(renderer.create_obj(Transform::origin().at(position), 1));
+                }
+            }
+            entities
+        }),
+        ("close up", |renderer| vec![
+            renderer.create_obj(Transform::origin().at(glam::Vec3::new(0.0, 0.2, 1.2)).scaled(glam::Vec3::splat(2.0)), 0),
+        ]),
+        // the prepass must not skip anything when the camera is inside a box, or right next to one
+        ("camera inside", |renderer| vec![
+            renderer.create_obj(Transform::origin().at(glam::Vec3::new(0.0, 0.4, 2.0)), 2),
+            renderer.create_obj(Transform::origin().at(glam::Vec3::new(0.35, 0.25, 1.5)), 1),
+            renderer.create_obj(Transform::origin().rotated(glam::Quat::from_axis_angle(glam::Vec3::Y, 0.3)), 0),
+        ]),
+    ];
+
+    println!("{:<16}{:>40}{:>40}", "", "without prepass (ms)", "with prepass (ms)");
+    println!(
+        "{:<16}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}{:>10}",
+        "scene", "prepass", "first", "second", "total", "prepass", "first", "second", "total", "speedup",
+    );
+    let mut frames_differ = false;
+    for (name, spawn) in scenes.iter() {
+        let entities = spawn(&mut renderer);
+
+        renderer.set_cone_prepass(false);
+        let (without, frame_without) = time_frames(&mut renderer, frames);
+        renderer.set_cone_prepass(true);
+        let (with, frame_with) = time_frames(&mut renderer, frames);
+
+        println!(
+            "{:<16}{}{}{:>9.2}x",
+            name, without.columns(), with.columns(), without.total() / with.total(),
+        );
+
+        // largest difference on a channel, for each pixel
+        let pixel_differences: Vec<u8> = frame_without.chunks(4)
+            .zip(frame_with.chunks(4))
+            .map(|(a, b)| a.iter().zip(b.iter()).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0))
+            .collect();
+        let differing_pixels = pixel_differences.iter().filter(|difference| **difference > 0).count();
+        // rays starting further along do not hit exactly the same point, which can round the shading differently
+        let beyond_rounding = pixel_differences.iter().filter(|difference| **difference > 1).count();
+        let max_difference = pixel_differences.iter().max().copied().unwrap_or(0);
+        if differing_pixels > 0 {
+            println!(
+                "  {differing_pixels} of {} pixels differ with the prepass, {beyond_rounding} by more than 1, up to {max_difference} on a channel",
+                pixel_differences.len(),
+            );
+            frames_differ = true;
+        }
+
+        for entity in entities.into_iter() {
+            renderer.despawn(entity);
+        }
+    }
+    if !frames_differ {
+        println!("The frames are identical with and without the prepass.");
+    }
+}
+
+/// Average time in milliseconds of each pass, or only of whole frames if the passes are not timed.
+#[derive(Default)]
+struct Timings {
+    cone_prepass: f64,
+    first_stage: f64,
+    second_stage: f64,
+    frame: Option<f64>,
+}
+
+impl Timings {
+    fn total(&self) -> f64 {
+        self.frame.unwrap_or(self.cone_prepass + self.first_stage + self.second_stage)
+    }
+
+    /// The passes and the total, the passes are left empty when they are not timed.
+    fn columns(&self) -> String {
+        match self.frame {
+            Some(frame) => format!("{:>10}{:>10}{:>10}{:>10.3}", "-", "-", "-", frame),
+            None => format!("{:>10.3}{:>10.3}{:>10.3}{:>10.3}", self.cone_prepass, self.first_stage, self.second_stage, self.total()),
+        }
+    }
+}
+
+/// Average timings of the frames, with the last frame.
+fn time_frames(renderer: &mut renderer::Renderer, frames: u32) -> (Timings, Vec<u8>) {
+    // the first frame uploads the instances
+    let mut frame = render_frame(renderer);
+    let mut timings = Timings::default();
+    let start = std::time::Instant::now();
+    for _ in 0..frames {
+        frame = render_frame(renderer);
+        match renderer.read_pass_timings() {
+            Ok(Some(pass_timings)) => {
+                timings.cone_prepass += pass_timings.cone_prepass / frames as f64;
+                timings.first_stage += pass_timings.first_stage / frames as f64;
+                timings.second_stage += pass_timings.second_stage / frames as f64;
+            },
+            Ok(None) => {},
+            Err(e) => {
+                println!("Unable to read the pass timings: {e:?}");
+                std::process::exit(1);
+            }
+        }
+    }
+    if renderer.read_pass_timings().ok().flatten().is_none() {
+        timings.frame = Some(start.elapsed().as_secs_f64() * 1000.0 / frames as f64);
+    }
+    (timings, frame)
+}
+fn render_frame(renderer: &mut renderer::Renderer) -> Vec<u8> {
+    if let Err(e) = renderer.render() {
+        println!("Unable to render: {e:?}");
+        std::process::exit(1);
+    }
+    // reading the frame back waits for the gpu to be done with it
+    match renderer.read_frame() {
+        Ok(pixels) => pixels,
+        Err(e) => {
+            println!("Unable to read back the frame: {e:?}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use morpheus::world::components::transform::Transform;
+
+pub use morpheus::*;
+
+fn main() {
+
+    let size = (640, 480);
+
+    let mut renderer = match renderer::Renderer::new_headless(size, renderer::RendererDescriptor::new()) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            println!("Unable to create renderer: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    renderer.load_csg(
+        0,
+        csg::csg!(
+            csg::BinOp::Inter => {
+                csg::Primitive::sphere(0.3)
+            } {
+                csg::Primitive::sphere(0.3).at(glam::Vec3::new(0.0, 0.2, 0.0))
+            }
+        )
+    ).expect("the tree fits the raymarcher stacks");
+
+    renderer.create_obj(Transform::origin().rotated(glam::Quat::from_axis_angle(glam::Vec3::Y, 0.3)), 0);
+
+    if let Err(e) = renderer.render() {
+        println!("Unable to render: {e:?}");
+        std::process::exit(1);
+    }
+
+    let pixels = match renderer.read_frame() {
+        Ok(pixels) => pixels,
+        Err(e) => {
+            println!("Unable to read back the frame: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    // write the frame as a binary ppm, that only needs rgb
+    let mut ppm = format!("P6\n{} {}\n255\n", size.0, size.1).into_bytes();
+    for pixel in pixels.chunks(4) {
+        ppm.extend_from_slice(&pixel[..3]);
+    }
+
+    if let Err(e) = std::fs::write("headless.ppm", ppm) {
+        println!("Unable to write frame: {e}");
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,81 @@
+use morpheus::world::components::transform::Transform;
+
+pub use morpheus::*;
+
+/// Check that a cube rotated in the csg renders the same as an aligned cube with a rotated transform.
+/// Both are rendered offscreen, and the frames are compared pixel by pixel.
+fn main() {
+
+    let size = (320, 240);
+    let position = glam::Vec3::new(0.1, 0.05, 0.0);
+    let rotation = glam::Quat::from_euler(glam::EulerRot::YXZ, 0.6, 0.4, 0.2);
+    let size_of_cube = glam::Vec3::new(0.25, 0.15, 0.1);
+
+    let mut renderer = match renderer::Renderer::new_headless(size, renderer::RendererDescriptor::new()) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            println!("Unable to create renderer: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    // the macro builds trees from operations, the union of a cube with itself is that cube
+    renderer.load_csg(
+        0,
+        csg::csg!(
+            csg::BinOp::Union => {
+                cube(position, rotation, size_of_cube)
+            } {
+                cube(position, rotation, size_of_cube)
+            }
+        )
+    ).expect("the tree fits the raymarcher stacks");
+    renderer.load_csg(
+        1,
+        csg::csg!(
+            csg::BinOp::Union => {
+                cube(glam::Vec3::ZERO, glam::Quat::IDENTITY, size_of_cube)
+            } {
+                cube(glam::Vec3::ZERO, glam::Quat::IDENTITY, size_of_cube)
+            }
+        )
+    ).expect("the tree fits the raymarcher stacks");
+
+    let rotated_in_csg = renderer.create_obj(Transform::origin(), 0);
+    let csg_frame = render_frame(&mut renderer);
+
+    renderer.despawn(rotated_in_csg);
+    renderer.create_obj(Transform::origin().at(position).rotated(rotation), 1);
+    let transform_frame = render_frame(&mut renderer);
+
+    // small float differences are expected, mostly on the edges
+    let differing_pixels = csg_frame.chunks(4)
+        .zip(transform_frame.chunks(4))
+        .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > 8))
+        .count();
+    let max_differing_pixels = (size.0 * size.1) as usize / 200;
+
+    if differing_pixels > max_differing_pixels {
+        println!("Rotated cubes differ: {differing_pixels} pixels out of {}", size.0 * size.1);
+        std::process::exit(1);
+    }
+    println!("Rotated cubes match ({differing_pixels} pixels differ)");
+}
+
+fn cube(offset: glam::Vec3, rotation: glam::Quat, size: glam::Vec3) -> csg::Primitive {
+    csg::Primitive::Cube { offset, rotation, size }
+}
+
+fn render_frame(renderer: &mut renderer::Renderer) -> Vec<u8> {
+    if let Err(e) = renderer.render() {
+        println!("Unable to render: {e:?}");
+        std::process::exit(1);
+    }
+    match renderer.read_frame() {
+        Ok(pixels) => pixels,
+        Err(e) => {
+            println!("Unable to read back the frame: {e:?}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use morpheus::sdf::{Primitive, Sdf};
+use morpheus::world::components::transform::Transform;
+
+pub use morpheus::*;
+
+/// Compares the iteration counts of plain sphere tracing (no over-relaxation, as the previous raymarcher)
+/// and over-relaxed sphere tracing (the default settings) on a few reference scenes.
+/// Iterations are counted by the raymarcher itself on the gpu, so this measures the shader as it is.
+/// Both use the hit distance following the pixel footprint, that is part of the shader.
+fn main() {
+
+    let size = (640, 480);
+
+    let mut renderer = match renderer::Renderer::new_headless(size, renderer::RendererDescriptor::new()) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            println!("Unable to create renderer: {e:?}");
+            std::process::exit(1);
+        }
+    };
+    // rays start at the proxy boxes, so only the tracing itself is measured
+    renderer.set_cone_prepass(false);
+
+    let scenes: [(&str, Sdf); 4] = [
+        ("sphere", csg::csg!(csg::Primitive::sphere(0.5)).into()),
+        // seen at a grazing angle, the worst case of plain sphere tracing
+        ("grazing plane", Sdf::from(Primitive::Plane { normal: glam::Vec3::Y, distance: -0.3 })
+            .union(csg::csg!(csg::Primitive::sphere(0.3).at(glam::Vec3::new(0.0, 0.0, -8.0))))),
+        // a row of cubes the rays pass right next to
+        ("cube row", Sdf::from(csg::csg!(csg::Primitive::Cube {
+                offset: glam::Vec3::new(0.0, 0.0, -1.0),
+                rotation: glam::Quat::IDENTITY,
+                size: glam::Vec3::splat(0.1),
+            }))
+            .repeated_limited(glam::Vec3::new(0.4, 0.0, 0.0), glam::Vec3::new(10.0, 0.0, 0.0))),
+        ("spheres union", csg::csg!(
+            csg::BinOp::Union => {
+                csg::BinOp::Union => {
+                    csg::Primitive::sphere(0.25).at(glam::Vec3::new(-0.3, 0.0, 0.0))
+                } {
+                    csg::Primitive::sphere(0.25).at(glam::Vec3::new(0.3, 0.0, 0.0))
+                }
+            } {
+                csg::Primitive::sphere(0.2).at(glam::Vec3::new(0.0, 0.35, -0.15))
+            }
+        ).into()),
+    ];
+
+    let relaxed = renderer.raymarch_settings();
+    let plain = relaxed.over_relaxation(1.0);
+
+    println!("{:<16}{:>12}{:>12}{:>10}{:>12}{:>12}{:>16}", "scene", "plain avg", "relaxed avg", "speedup", "plain max", "relaxed max", "pixels differ");
+    for (asset_id, (name, sdf)) in scenes.into_iter().enumerate() {
+        renderer.load_csg(asset_id as u64, sdf).expect("the tree fits the raymarcher stacks");
+        let entity = renderer.create_obj(Transform::origin(), asset_id as u64);
+
+        renderer.set_raymarch_settings(plain);
+        let (plain_iterations, plain_frame) = measure(&mut renderer);
+        renderer.set_raymarch_settings(relaxed);
+        let (relaxed_iterations, relaxed_frame) = measure(&mut renderer);
+
+        // average over the pixels that were marched at all
+        let marched = plain_iterations.iter()
+            .zip(relaxed_iterations.iter())
+            .filter(|(plain, relaxed)| **plain > 0 || **relaxed > 0)
+            .count()
+            .max(1) as f64;
+        let plain_avg = plain_iterations.iter().sum::<u32>() as f64 / marched;
+        let relaxed_avg = relaxed_iterations.iter().sum::<u32>() as f64 / marched;
+        let plain_max = plain_iterations.iter().max().copied().unwrap_or(0);
+        let relaxed_max = relaxed_iterations.iter().max().copied().unwrap_or(0);
+
+        let differing_pixels = plain_frame.chunks(4)
+            .zip(relaxed_frame.chunks(4))
+            .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > 8))
+            .count();
+
+        println!(
+            "{:<16}{:>12.2}{:>12.2}{:>9.2}x{:>12}{:>12}{:>16}",
+            name, plain_avg, relaxed_avg, plain_avg / relaxed_avg.max(f64::EPSILON), plain_max, relaxed_max, differing_pixels,
+        );
+
+        renderer.despawn(entity);
+    }
+}
+
+/// Iteration counts of each pixel, and the frame rendered with the same settings.
+fn measure(renderer: &mut renderer::Renderer) -> (Vec<u32>, Vec<u8>) {
+    let iterations = match renderer.read_iterations() {
+        Ok(iterations) => iterations,
+        Err(e) => {
+            println!("Unable to count the iterations: {e:?}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = renderer.render() {
+        println!("Unable to render: {e:?}");
+        std::process::exit(1);
+    }
+    match renderer.read_frame() {
+        Ok(pixels) => (iterations, pixels),
+        Err(e) => {
+            println!("Unable to read back the frame: {e:?}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -14,7 +14,7 @@ fn main() {
     let window = WindowBuilder::new().build(&event_loop).unwrap();
     let start_size = (window.inner_size().width, window.inner_size().height);
 
-    let mut renderer = match renderer::Renderer::new(&window, start_size) {
+    let mut renderer = match renderer::Renderer::new(&window, start_size, renderer::RendererDescriptor::new()) {
         Ok(renderer) => renderer,
         Err(e) => {
             println!("Unable to create renderer: {e:?}");
@@ -31,7 +31,7 @@ fn main() {
                 csg::Primitive::sphere(0.3).at(glam::Vec3::new(0.0, 0.2, 0.0))
             }
         )
-    );
+    ).expect("the tree fits the raymarcher stacks");
 
     renderer.create_obj(Transform::origin().rotated(glam::Quat::from_axis_angle(glam::Vec3::Y, 0.3)), 0);
 
@@ -56,11 +56,8 @@ fn main() {
                 ..
             } => match renderer.render() {
                 Ok(_) => {}
-                // Reconfigure the surface if lost
-                Err(wgpu::SurfaceError::Lost) => todo!("handle surface lost"),
                 // The system is out of memory, we should probably quit
-                Err(wgpu::SurfaceError::OutOfMemory) => elwt.exit(),
-                // All other errors (Outdated, Timeout) should be resolved by the next frame
+                Err(error::MorpheusError::OutOfMemory) => elwt.exit(),
                 Err(e) => eprintln!("{:?}", e),
             },
             // key handling
@@ -5,6 +5,34 @@ pub enum MorpheusError {
     RequestDeviceError(wgpu::RequestDeviceError),
     WgpuInternal(wgpu::Error),
     NoAvailableAdapter,
+    BufferMapping(wgpu::BufferAsyncError),
+    /// The adapter is missing these features, that are required by the renderer descriptor.
+    UnsupportedFeatures(wgpu::Features),
+    /// The adapter can't reach the requested limit.
+    UnsupportedLimit {
+        name: &'static str,
+        requested: u64,
+        allowed: u64,
+    },
+    /// The surface does not support the requested present mode.
+    UnsupportedPresentMode(wgpu::PresentMode),
+    /// The surface does not support the requested alpha mode.
+    UnsupportedAlphaMode(wgpu::CompositeAlphaMode),
+    /// There is no memory left to render a frame. This is not recoverable.
+    OutOfMemory,
+    /// Tried to read back a frame from a renderer that draws to a window surface.
+    NotHeadless,
+    /// Tried to render a frame on its own with an embedded renderer, that can only record frames with `render_to`.
+    NoRenderTarget,
+    /// The csg tree needs more room on the stacks of the raymarcher than it has.
+    CsgTooDeep {
+        /// Height of the value stack the tree needs.
+        values: usize,
+        /// Number of nested domain operations.
+        points: usize,
+        /// Height of both stacks.
+        max: usize,
+    },
 }
 
 impl From<wgpu::Error> for MorpheusError {
@@ -17,4 +45,10 @@ impl From<wgpu::RequestDeviceError> for MorpheusError {
     fn from(value: wgpu::RequestDeviceError) -> Self {
         MorpheusError::RequestDeviceError(value)
     }
+}
+
+impl From<wgpu::BufferAsyncError> for MorpheusError {
+    fn from(value: wgpu::BufferAsyncError) -> Self {
+        MorpheusError::BufferMapping(value)
+    }
 }
\ No newline at end of file
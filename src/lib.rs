@@ -1,6 +1,7 @@
 pub mod renderer;
 pub mod error;
 pub mod world;
+pub mod sdf;
 pub(crate) mod utils;
 
 // export the csg lib
@@ -1,4 +1,5 @@
-use crate::world::{camera::Camera, components::transform::Transform};
+use crate::sdf::Sdf;
+use crate::world::{camera::Camera, components::transform::Transform, entity::Entity};
 
 use self::{asset_manager::AssetManager, assets::csg::CsgObjectAsset};
 
@@ -7,9 +8,16 @@ pub(crate) mod asset_manager;
 pub(crate) mod buffer;
 pub(crate) mod deferred_renderer;
 pub(crate) mod has_bind_group_layout;
+pub(crate) mod offscreen_target;
+pub(crate) mod raymarch_settings;
+pub(crate) mod renderer_descriptor;
 pub(crate) mod rendering_state;
 pub(crate) mod screen_resolution;
 
+pub use self::renderer_descriptor::RendererDescriptor;
+pub use self::raymarch_settings::{RaymarchSettings, RaymarchQuality};
+pub use self::deferred_renderer::pass_timer::PassTimings;
+
 
 /// Central morpheus app renderer.
 /// This include the wgpu state, as well as the world with entities and components.
@@ -20,20 +28,42 @@ pub struct Renderer {
 }
 
 impl Renderer {
-    pub fn new<T>(handle: &T, start_size: (u32, u32)) -> Result<Renderer, crate::error::MorpheusError>
+    pub fn new<T>(handle: &T, start_size: (u32, u32), descriptor: RendererDescriptor) -> Result<Renderer, crate::error::MorpheusError>
         where T: raw_window_handle::HasRawWindowHandle + raw_window_handle::HasRawDisplayHandle,
     {
-        let state = rendering_state::RenderingState::new(handle, start_size)?;
+        let state = rendering_state::RenderingState::new(handle, start_size, &descriptor)?;
+        Ok(Renderer::with_state(state, start_size))
+    }
+
+    /// Create a renderer without any window, that renders into an owned texture.
+    /// The rendered frames can be retrieved with [`Renderer::read_frame`].
+    /// If no gpu is available, this will fall back on a software adapter.
+    /// Surface settings of the descriptor (present and alpha modes) are ignored.
+    pub fn new_headless(size: (u32, u32), descriptor: RendererDescriptor) -> Result<Renderer, crate::error::MorpheusError> {
+        let state = rendering_state::RenderingState::new_headless(size, &descriptor)?;
+        Ok(Renderer::with_state(state, size))
+    }
+
+    /// Create a renderer on top of the device and queue of a host application, to compose it into an existing engine.
+    /// The renderer does not own any target: frames are recorded with [`Renderer::render_to`]
+    /// into views of the given format.
+    /// Only the raymarch settings and cone prepass of the descriptor are used, the device is already chosen by the host.
+    pub fn new_embedded(device: std::sync::Arc<wgpu::Device>, queue: std::sync::Arc<wgpu::Queue>, target_format: wgpu::TextureFormat, size: (u32, u32), descriptor: RendererDescriptor) -> Renderer {
+        let state = rendering_state::RenderingState::with_device(device, queue, target_format, size, &descriptor);
+        Renderer::with_state(state, size)
+    }
+
+    fn with_state(state: rendering_state::RenderingState, start_size: (u32, u32)) -> Renderer {
         let main_camera = Camera::new(&state.device, glam::vec3(0., 0.4, 2.0), start_size);
         let world = crate::world::World::new(main_camera);
 
         let assets = AssetManager::new();
 
-        Ok(Renderer {
+        Renderer {
             state,
             assets,
             world,
-        })
+        }
     }
 
     pub fn resize(&mut self, new_size: (u32, u32)) {
@@ -41,21 +71,114 @@ impl Renderer {
         self.world.main_camera_mut().viewport_resize(&self.state.queue, new_size);
     }
 
-    pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        self.state.update_uniforms(&mut self.world);
-        // check world rebuild
+    /// Render a frame into the renderer target and present it.
+    /// Lost or outdated surfaces are reconfigured internally, so this only fails when we are out of memory.
+    /// Embedded renderers do not have a target, they fail with [`crate::error::MorpheusError::NoRenderTarget`]
+    /// and should use [`Renderer::render_to`] instead.
+    pub fn render(&mut self) -> Result<(), crate::error::MorpheusError> {
+        self.prepare_frame();
+        self.state.render(&self.world, &self.assets)
+    }
+
+    /// Record a frame into the given encoder, writing the result into the view.
+    /// The view should have the format the renderer was created with, and the size of the last resize.
+    /// Submitting the encoder is left to the caller, so this can be one pass of a bigger frame.
+    pub fn render_to(&mut self, view: &wgpu::TextureView, encoder: &mut wgpu::CommandEncoder) {
+        self.prepare_frame();
+        self.state.render_to(&self.world, &self.assets, view, encoder);
+    }
+
+    fn prepare_frame(&mut self) {
+        // global transforms must be up to date before they are uploaded
+        self.world.propagate_transforms();
+        // check world rebuild, assets must be loaded before the renderer prepares their pipelines
         if self.assets.dirty() {
             self.assets.reload(&self.state.device, &self.state.queue);
+            // replaced assets may have been the last ones with their structure
+            self.state.renderer.evict_unused_pipelines(&self.assets);
         }
-        self.state.render(&mut self.world, &self.assets)
+        self.state.update_uniforms(&mut self.world, &self.assets);
+    }
+
+    /// Read back the last rendered frame of a headless renderer.
+    /// The pixels are rgba8 (srgb), row by row from the top left corner.
+    pub fn read_frame(&self) -> Result<Vec<u8>, crate::error::MorpheusError> {
+        self.state.read_frame()
+    }
+
+    /// Render a frame counting the iterations the raymarcher takes for each pixel, and read the counts back.
+    /// Only headless renderers can read them, like frames.
+    /// Counts are row by row from the top left corner, summed over the objects covering the pixel, and exact up to 2048.
+    pub fn read_iterations(&mut self) -> Result<Vec<u32>, crate::error::MorpheusError> {
+        self.prepare_frame();
+        self.state.read_iterations(&self.world, &self.assets)
+    }
+
+    /// Gpu time spent in each pass of the last submitted frame, waiting for the gpu to be done with it.
+    /// Passes are only timed when the device has [`wgpu::Features::TIMESTAMP_QUERY`],
+    /// requested with [`RendererDescriptor::features`], otherwise this is None.
+    pub fn read_pass_timings(&self) -> Result<Option<PassTimings>, crate::error::MorpheusError> {
+        self.state.read_pass_timings()
     }
 
-    pub fn load_csg(&mut self, asset_id: u64, csg: csg::CSG) {
-        let asset = CsgObjectAsset::new(&self.state.device, csg);
+    /// Settings used to raymarch the objects that do not override them.
+    pub fn raymarch_settings(&self) -> RaymarchSettings {
+        self.state.renderer.raymarch_settings()
+    }
+
+    /// Change the raymarching settings, applied from the next frame on.
+    /// Presets can be switched with [`RaymarchSettings::preset`].
+    pub fn set_raymarch_settings(&mut self, raymarch_settings: RaymarchSettings) {
+        self.state.renderer.set_raymarch_settings(raymarch_settings);
+    }
+
+    /// Whether a low resolution cone marching pass finds the empty space in front of the objects
+    /// before they are raymarched at full resolution.
+    pub fn cone_prepass(&self) -> bool {
+        self.state.renderer.cone_prepass()
+    }
+
+    /// Toggle the cone marching prepass. It saves iterations when objects cover a lot of the screen,
+    /// and costs an extra low resolution pass otherwise.
+    pub fn set_cone_prepass(&mut self, enabled: bool) {
+        self.state.renderer.set_cone_prepass(enabled);
+    }
+
+    /// Load a tree, built with the csg crate or with the primitives and operations of [`crate::sdf`].
+    /// Trees needing more than 8 values on the raymarcher stack, or more than 8 nested domain operations,
+    /// are rejected with [`crate::error::MorpheusError::CsgTooDeep`].
+    pub fn load_csg(&mut self, asset_id: u64, sdf: impl Into<Sdf>) -> Result<(), crate::error::MorpheusError> {
+        let asset = CsgObjectAsset::new(&self.state.device, sdf)?;
         self.assets.load(asset_id, asset);
+        Ok(())
+    }
+
+    /// Load a csg that is compiled into its own shader. It renders faster than with [`Renderer::load_csg`],
+    /// but the first frame using a new tree structure has to build its pipelines.
+    /// Pipelines are shared by the assets with the same structure, whatever the values in their nodes,
+    /// and dropped once no loaded asset has this structure anymore.
+    /// Trees are limited the same way as with [`Renderer::load_csg`].
+    pub fn load_static_csg(&mut self, asset_id: u64, sdf: impl Into<Sdf>) -> Result<(), crate::error::MorpheusError> {
+        let asset = CsgObjectAsset::new_compiled(&self.state.device, sdf)?;
+        self.assets.load(asset_id, asset);
+        Ok(())
+    }
+
+    /// Create an object rendering the csg asset, and get a handle to it.
+    pub fn create_obj(&mut self, transform: Transform, asset_id: u64) -> Entity {
+        self.world.spawn(transform, asset_id)
+    }
+
+    /// Remove an object. Returns false if it was already despawned.
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        self.world.despawn(entity)
+    }
+
+    pub fn world(&self) -> &crate::world::World {
+        &self.world
     }
 
-    pub fn create_obj(&mut self, transform: Transform, asset_id: u64) {
-        self.world.add_obj(transform, asset_id);
+    pub fn world_mut(&mut self) -> &mut crate::world::World {
+        &mut self.world
     }
 }
\ No newline at end of file
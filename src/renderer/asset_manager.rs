@@ -53,7 +53,17 @@ impl AssetManager {
         map.map.get(&key)
     }
 
-    
+    /// All the loaded assets of the type T, the ones loaded since the last reload are not there yet.
+    pub(crate) fn values<T: 'static + AssetTrait>(&self) -> impl Iterator<Item = &T>
+        where AssetMap<T>: AssetMapTrait
+    {
+        self.assets.get(&std::any::TypeId::of::<AssetMap<T>>())
+            // SAFETY: safe because of our guarantee that the value at type id T is T
+            .map(|map| map.as_any().downcast_ref::<AssetMap<T>>().unwrap())
+            .into_iter()
+            .flat_map(|map| map.map.values())
+    }
+
     pub(crate) fn dirty(&self) -> bool {
         self.dirty
     }
@@ -1,25 +1,54 @@
+pub(crate) mod bounding_box;
+pub(crate) mod compiled_sdf;
 pub(crate) mod csg_buffer;
 
+use crate::error::MorpheusError;
 use crate::renderer::asset_manager::asset::AssetTrait;
+use crate::sdf::Sdf;
 
-use self::csg_buffer::CsgBuffer;
+use self::{bounding_box::BoundingBox, compiled_sdf::CompiledSdf, csg_buffer::{CsgBuffer, StackDepths, MAX_STACK_DEPTH}};
 
 
 
 pub struct CsgObjectAsset {
     buffer: CsgBuffer,
-    csg: csg::CSG,
+    bounding_box: BoundingBox,
+    /// Dedicated scene_sdf, if the asset is rendered with its own pipeline.
+    compiled: Option<CompiledSdf>,
+    sdf: Sdf,
 }
 
 impl CsgObjectAsset {
 
-    pub fn new(device: &wgpu::Device, csg: csg::CSG) -> CsgObjectAsset {
-        let buffer = CsgBuffer::new(device, &csg);
+    /// Fails if the tree needs deeper stacks than the raymarcher has, see [`MorpheusError::CsgTooDeep`].
+    pub fn new(device: &wgpu::Device, sdf: impl Into<Sdf>) -> Result<CsgObjectAsset, MorpheusError> {
+        let sdf = sdf.into();
+        check_stack_depths(&sdf)?;
+        let bounding_box = BoundingBox::from_sdf(&sdf);
+        let buffer = CsgBuffer::new(device, &sdf, &bounding_box);
 
-        CsgObjectAsset {
+        Ok(CsgObjectAsset {
             buffer,
-            csg,
-        }
+            bounding_box,
+            compiled: None,
+            sdf,
+        })
+    }
+
+    /// Asset rendered with a pipeline compiled for its tree, instead of the interpreter.
+    /// Faster to raymarch, but building the pipeline is expensive: this is meant for assets that don't change.
+    /// Trees the compiler does not support are interpreted.
+    pub fn new_compiled(device: &wgpu::Device, sdf: impl Into<Sdf>) -> Result<CsgObjectAsset, MorpheusError> {
+        let sdf = sdf.into();
+        let compiled = CompiledSdf::new(&sdf);
+        Ok(CsgObjectAsset {
+            compiled,
+            ..CsgObjectAsset::new(device, sdf)?
+        })
+    }
+
+    pub(crate) fn compiled(&self) -> Option<&CompiledSdf> {
+        self.compiled.as_ref()
     }
 
     pub fn bind_group(&self) -> &wgpu::BindGroup {
@@ -29,6 +58,25 @@ impl CsgObjectAsset {
 
 impl AssetTrait for CsgObjectAsset {
     fn relaod(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
-        self.buffer.update_csg(device, queue, &self.csg);
+        // the tree can't change after it is checked in new, reloading can't overflow the stacks
+        debug_assert!(check_stack_depths(&self.sdf).is_ok());
+        self.bounding_box = BoundingBox::from_sdf(&self.sdf);
+        self.buffer.update_sdf(device, queue, &self.sdf, &self.bounding_box);
+        if self.compiled.is_some() {
+            self.compiled = CompiledSdf::new(&self.sdf);
+        }
     }
-}
\ No newline at end of file
+}
+
+/// The interpreters have fixed size stacks, trees needing more would read and write out of them.
+fn check_stack_depths(sdf: &Sdf) -> Result<(), MorpheusError> {
+    let depths = StackDepths::of(&csg_buffer::encoded_ids(sdf));
+    if !depths.fits() {
+        return Err(MorpheusError::CsgTooDeep {
+            values: depths.values,
+            points: depths.points,
+            max: MAX_STACK_DEPTH,
+        });
+    }
+    Ok(())
+}
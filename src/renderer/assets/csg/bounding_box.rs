@@ -0,0 +1,260 @@
+use crate::sdf::{DomainOp, Operation, Primitive, Sdf};
+
+/// Axis aligned bounding box of a csg object, in object space.
+/// This is conservative: the object is always inside, but the box might be bigger than needed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BoundingBox {
+    pub(crate) min: glam::Vec3,
+    pub(crate) max: glam::Vec3,
+}
+
+impl BoundingBox {
+    /// Box that contains nothing. Union with it is a no-op, intersection with it is empty.
+    pub(crate) const EMPTY: BoundingBox = BoundingBox {
+        min: glam::Vec3::INFINITY,
+        max: glam::Vec3::NEG_INFINITY,
+    };
+
+    /// Compute the bounds of the tree.
+    pub(crate) fn from_sdf(sdf: &Sdf) -> BoundingBox {
+        match sdf {
+            Sdf::Csg(csg) => BoundingBox::from_csg(csg),
+            Sdf::Primitive(primitive) => BoundingBox::from_primitive(primitive),
+            Sdf::Operation(operation, first, second) => {
+                let (first, second) = (BoundingBox::from_sdf(first), BoundingBox::from_sdf(second));
+                match operation {
+                    Operation::Union => first.union(&second),
+                    // the blend adds matter between the objects, up to the blend radius away
+                    Operation::SmoothUnion { radius } => first.union(&second).grown(radius.max(0.0)),
+                    // smoothing an intersection or a difference only removes matter, the hard bounds hold
+                    Operation::Inter | Operation::SmoothInter { .. } => first.intersection(&second),
+                    // the second object is carved out of the first, so it stays in the first box
+                    Operation::Diff | Operation::SmoothDiff { .. } => first,
+                }
+            },
+            Sdf::Domain(operation, inner) => BoundingBox::from_sdf(inner).through_domain(operation),
+        }
+    }
+
+    /// Compute the bounds of the csg tree.
+    /// This evaluates the tree in reverse polish notation, in the same way the gpu does for the sdf.
+    fn from_csg(csg: &csg::CSG) -> BoundingBox {
+        let mut stack: Vec<BoundingBox> = Vec::with_capacity(csg.node_count());
+
+        for node in csg.nodes().rev() {
+            match node {
+                csg::node::CsgNode::Primitive(primitive) => stack.push(BoundingBox::from_csg_primitive(primitive)),
+                csg::node::CsgNode::BinOp(_) => {
+                    // binary operation on the two last bounds of the stack
+                    let (Some(top), Some(below)) = (stack.pop(), stack.pop()) else {
+                        // malformed tree, nothing to bound
+                        return BoundingBox::EMPTY;
+                    };
+                    let result = match node.id() {
+                        // inter: the object is in both boxes
+                        4 => below.intersection(&top),
+                        // diff: the top object carved by the one below, so it stays in the top box
+                        5 => top,
+                        // union, or anything we don't know of: both boxes is conservative
+                        _ => below.union(&top),
+                    };
+                    stack.push(result);
+                }
+            }
+        }
+
+        stack.pop().unwrap_or(BoundingBox::EMPTY)
+    }
+
+    fn from_csg_primitive(primitive: &csg::Primitive) -> BoundingBox {
+        match primitive {
+            csg::Primitive::Sphere { radius, offset } => BoundingBox {
+                min: *offset - glam::Vec3::splat(*radius),
+                max: *offset + glam::Vec3::splat(*radius),
+            },
+            // size are the half extents of the box
+            csg::Primitive::Cube { offset, rotation, size } => BoundingBox::placed(*offset, *rotation, *size),
+        }
+    }
+
+    fn from_primitive(primitive: &Primitive) -> BoundingBox {
+        match primitive {
+            // the ring lies in the xz plane
+            Primitive::Torus { offset, rotation, major_radius, minor_radius } => {
+                let extent = major_radius + minor_radius;
+                BoundingBox::placed(*offset, *rotation, glam::Vec3::new(extent, *minor_radius, extent))
+            },
+            // cylinders, cones and prisms are along the y axis
+            Primitive::Cylinder { offset, rotation, radius, half_height }
+            | Primitive::Cone { offset, rotation, radius, half_height } => {
+                BoundingBox::placed(*offset, *rotation, glam::Vec3::new(*radius, *half_height, *radius))
+            },
+            Primitive::Capsule { offset, rotation, radius, half_height } => {
+                BoundingBox::placed(*offset, *rotation, glam::Vec3::new(*radius, half_height + radius, *radius))
+            },
+            Primitive::Plane { normal, distance } => BoundingBox::half_space(*normal, *distance),
+            Primitive::Ellipsoid { offset, rotation, radii } => BoundingBox::placed(*offset, *rotation, *radii),
+            // the rounding is inside the size
+            Primitive::RoundedBox { offset, rotation, size, .. } => BoundingBox::placed(*offset, *rotation, *size),
+            Primitive::HexPrism { offset, rotation, radius, half_height } => {
+                // the radius goes to the flat sides, corners are further away
+                let corner_radius = radius * 2.0 / 3.0f32.sqrt();
+                BoundingBox::placed(*offset, *rotation, glam::Vec3::new(corner_radius, *half_height, corner_radius))
+            },
+        }
+    }
+
+    /// Bounds of the subtree once the domain operation is applied to it.
+    fn through_domain(&self, operation: &DomainOp) -> BoundingBox {
+        if self.is_empty() {
+            return *self;
+        }
+        match operation {
+            DomainOp::Repeat { period } => {
+                // repeated axes go on forever
+                let repeated = period.cmpgt(glam::Vec3::ZERO);
+                BoundingBox {
+                    min: glam::Vec3::select(repeated, glam::Vec3::NEG_INFINITY, self.min),
+                    max: glam::Vec3::select(repeated, glam::Vec3::INFINITY, self.max),
+                }
+            },
+            DomainOp::RepeatLimited { period, count } => {
+                // copies are up to count periods away on each side
+                let reach = period.max(glam::Vec3::ZERO) * count.max(glam::Vec3::ZERO);
+                BoundingBox {
+                    min: self.min - reach,
+                    max: self.max + reach,
+                }
+            },
+            DomainOp::Mirror { x, y, z } => {
+                // only the positive side of mirrored axes is evaluated, and reflected on the negative side
+                let mirrored = glam::BVec3::new(*x, *y, *z);
+                let positive_max = self.max.max(glam::Vec3::ZERO);
+                BoundingBox {
+                    min: glam::Vec3::select(mirrored, -positive_max, self.min),
+                    max: glam::Vec3::select(mirrored, positive_max, self.max),
+                }
+            },
+            DomainOp::Twist { .. } => {
+                // twisting rotates around y, so it keeps the distance to the y axis
+                let radius = self.max_distance_to_axis(glam::Vec3::new(1.0, 0.0, 1.0));
+                BoundingBox {
+                    min: glam::Vec3::new(-radius, self.min.y, -radius),
+                    max: glam::Vec3::new(radius, self.max.y, radius),
+                }
+            },
+            DomainOp::Bend { .. } => {
+                // bending rotates around z, so it keeps the distance to the z axis
+                let radius = self.max_distance_to_axis(glam::Vec3::new(1.0, 1.0, 0.0));
+                BoundingBox {
+                    min: glam::Vec3::new(-radius, -radius, self.min.z),
+                    max: glam::Vec3::new(radius, radius, self.max.z),
+                }
+            },
+            DomainOp::Transform { translation, rotation, scale } => self.transformed(*translation, *rotation, *scale),
+        }
+    }
+
+    /// Bounds of the box scaled, rotated and moved by the transform.
+    fn transformed(&self, translation: glam::Vec3, rotation: glam::Quat, scale: glam::Vec3) -> BoundingBox {
+        if self.is_empty() {
+            return *self;
+        }
+        // scale first, negative scales flip the box
+        let (a, b) = (self.min * scale, self.max * scale);
+        let (min, max) = (a.min(b), a.max(b));
+        if !(min.is_finite() && max.is_finite()) {
+            // unbounded sides only stay axis aligned without rotation
+            return match rotation.abs_diff_eq(glam::Quat::IDENTITY, 1.0e-6) {
+                true => BoundingBox { min: min + translation, max: max + translation },
+                false => BoundingBox { min: glam::Vec3::NEG_INFINITY, max: glam::Vec3::INFINITY },
+            };
+        }
+        let center = (min + max) * 0.5;
+        BoundingBox::placed(translation + rotation * center, rotation, (max - min) * 0.5)
+    }
+
+    /// Furthest distance between a point of the box and the axis, given by the mask of the plane orthogonal to it.
+    fn max_distance_to_axis(&self, plane_mask: glam::Vec3) -> f32 {
+        let furthest_corner = self.min.abs().max(self.max.abs());
+        (furthest_corner * plane_mask).length()
+    }
+
+    /// Bounds of a box of the given half extents, rotated and moved to the offset.
+    fn placed(offset: glam::Vec3, rotation: glam::Quat, half_extents: glam::Vec3) -> BoundingBox {
+        // project the rotated extents on each axis
+        let rotation = glam::Mat3::from_quat(rotation);
+        let half_extents = rotation.x_axis.abs() * half_extents.x
+            + rotation.y_axis.abs() * half_extents.y
+            + rotation.z_axis.abs() * half_extents.z;
+        BoundingBox {
+            min: offset - half_extents,
+            max: offset + half_extents,
+        }
+    }
+
+    /// Bounds of the half space dot(p, normal) <= distance.
+    /// This is infinite, unless the normal is along an axis where one side is bounded.
+    fn half_space(normal: glam::Vec3, distance: f32) -> BoundingBox {
+        let mut bounding_box = BoundingBox {
+            min: glam::Vec3::NEG_INFINITY,
+            max: glam::Vec3::INFINITY,
+        };
+        for axis in 0..3 {
+            let others_are_zero = (0..3).filter(|other| *other != axis).all(|other| normal[other] == 0.0);
+            if others_are_zero && normal[axis] > 0.0 {
+                bounding_box.max[axis] = distance / normal[axis];
+            }
+            else if others_are_zero && normal[axis] < 0.0 {
+                bounding_box.min[axis] = distance / normal[axis];
+            }
+        }
+        bounding_box
+    }
+
+    pub(crate) fn union(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub(crate) fn intersection(&self, other: &BoundingBox) -> BoundingBox {
+        BoundingBox {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    pub(crate) fn grown(&self, amount: f32) -> BoundingBox {
+        BoundingBox {
+            min: self.min - glam::Vec3::splat(amount),
+            max: self.max + glam::Vec3::splat(amount),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    /// Bytes of the box, as expected by the raymarcher (two vec3 aligned on 16 bytes).
+    /// An empty box is sent as a single point, so the proxy geometry is degenerate.
+    /// Unbounded sides (planes) are clamped, the proxy geometry can't go to infinity.
+    pub(crate) fn to_gpu_data(self) -> [u8; BOUNDING_BOX_GPU_SIZE] {
+        let (min, max) = match self.is_empty() {
+            true => (glam::Vec3::ZERO, glam::Vec3::ZERO),
+            false => (
+                self.min.max(glam::Vec3::splat(-MAX_GPU_EXTENT)),
+                self.max.min(glam::Vec3::splat(MAX_GPU_EXTENT)),
+            ),
+        };
+        let data = [min.x, min.y, min.z, 0.0, max.x, max.y, max.z, 0.0];
+        bytemuck::cast(data)
+    }
+}
+
+/// Furthest the bounding box sent to the gpu can go from the object origin.
+const MAX_GPU_EXTENT: f32 = 1.0e4;
+
+/// Number of bytes the bounding box takes on the gpu.
+pub(crate) const BOUNDING_BOX_GPU_SIZE: usize = 2 * 4 * 4;
@@ -0,0 +1,243 @@
+use std::fmt::Write;
+
+use crate::sdf::Sdf;
+
+use super::csg_buffer;
+
+
+/// `scene_sdf` and its gradient written for a single csg tree, to replace the interpreters in the raymarcher.
+/// The nodes are evaluated in the same order as the interpreter would, but without the loop,
+/// the switch on the node ids and the stacks: every value gets its own variable.
+/// The node data is still read from the csg buffer, so trees with the same structure share the same code.
+pub(crate) struct CompiledSdf {
+    /// Encoded node ids, the structure of the tree and the key of the compiled pipelines.
+    node_ids: Vec<u32>,
+    source: String,
+}
+
+impl CompiledSdf {
+    /// Returns None if the tree has nodes the compiler does not know, it has to be interpreted.
+    pub(crate) fn new(sdf: &Sdf) -> Option<CompiledSdf> {
+        let node_ids = csg_buffer::encoded_ids(sdf);
+        let source = generate_scene_sdf(&node_ids)?;
+
+        Some(CompiledSdf {
+            node_ids,
+            source,
+        })
+    }
+
+    pub(crate) fn node_ids(&self) -> &[u32] {
+        &self.node_ids
+    }
+
+    /// WGSL definitions of `scene_sdf(at: vec3<f32>) -> f32` and `scene_sdf_gradient(at: vec3<f32>) -> vec4<f32>`.
+    pub(crate) fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+/// Write scene_sdf and scene_sdf_gradient for the encoded nodes, see the interpreters in csg_interpreter.wgsl.
+fn generate_scene_sdf(node_ids: &[u32]) -> Option<String> {
+    let mut source = generate_function(node_ids, false)?;
+    source.push('\n');
+    source.push_str(&generate_function(node_ids, true)?);
+    Some(source)
+}
+
+/// Write scene_sdf, or scene_sdf_gradient whose values carry their gradient in xyz and their distance in w.
+fn generate_function(node_ids: &[u32], gradient: bool) -> Option<String> {
+    let mut source = match gradient {
+        false => String::from("fn scene_sdf(at: vec3<f32>) -> f32 {\n    let p0 = at;\n"),
+        true => String::from("fn scene_sdf_gradient(at: vec3<f32>) -> vec4<f32> {\n    let p0 = at;\n"),
+    };
+    // names of the variables holding the values the interpreter would have on its stacks
+    let mut sdf_stack: Vec<String> = Vec::new();
+    let mut point_stack: Vec<String> = vec![String::from("p0")];
+    // index of the node that opened each scope, the gradient goes back through its operation
+    let mut scope_stack: Vec<usize> = Vec::new();
+
+    for (index, &id) in node_ids.iter().enumerate() {
+        let point = point_stack.last()?.clone();
+        match id {
+            0 | 1 | 6..=13 => {
+                let function = match (id, gradient) {
+                    (_, true) => "primitive_gradient",
+                    (0, _) => "sphere_sdf",
+                    (1, _) => "cube_sdf",
+                    (6, _) => "torus_sdf",
+                    (7, _) => "cylinder_sdf",
+                    (8, _) => "capsule_sdf",
+                    (9, _) => "cone_sdf",
+                    (10, _) => "plane_sdf",
+                    (11, _) => "ellipsoid_sdf",
+                    (12, _) => "rounded_box_sdf",
+                    _ => "hex_prism_sdf",
+                };
+                let sdf = format!("d{index}");
+                writeln!(source, "    let {sdf} = {function}({point}, {index}u);").ok()?;
+                sdf_stack.push(sdf);
+            },
+            3..=5 | 14..=16 | 23 | 24 => {
+                // same operand order as the interpreter: the second value is the top of the stack
+                let second = sdf_stack.pop()?;
+                let first = sdf_stack.pop()?;
+                let operation = match (id, gradient) {
+                    (3, false) => format!("min({first}, {second})"),
+                    (4, false) => format!("max({first}, {second})"),
+                    (5, false) => format!("max(-{first}, {second})"),
+                    (14, false) => format!("smin({first}, {second}, blend_radius({index}u))"),
+                    (15, false) => format!("smax({first}, {second}, blend_radius({index}u))"),
+                    (16, false) => format!("smax(-{first}, {second}, blend_radius({index}u))"),
+                    (23, false) => format!("max({first}, -{second})"),
+                    (_, false) => format!("smax({first}, -{second}, blend_radius({index}u))"),
+                    // hard operations keep the gradient of the value they select
+                    (3, true) => format!("select({second}, {first}, {first}.w < {second}.w)"),
+                    (4, true) => format!("select({second}, {first}, {first}.w > {second}.w)"),
+                    (5, true) => format!("select({second}, -{first}, -{first}.w > {second}.w)"),
+                    (14, true) => format!("smin_gradient({first}, {second}, blend_radius({index}u))"),
+                    (15, true) => format!("smax_gradient({first}, {second}, blend_radius({index}u))"),
+                    (16, true) => format!("smax_gradient(-{first}, {second}, blend_radius({index}u))"),
+                    (23, true) => format!("select(-{second}, {first}, {first}.w > -{second}.w)"),
+                    (_, true) => format!("smax_gradient({first}, -{second}, blend_radius({index}u))"),
+                };
+                let sdf = format!("d{index}");
+                writeln!(source, "    let {sdf} = {operation};").ok()?;
+                sdf_stack.push(sdf);
+            },
+            17..=22 => {
+                let function = match id {
+                    17 => "repeat_domain",
+                    18 => "repeat_limited_domain",
+                    19 => "mirror_domain",
+                    20 => "twist_domain",
+                    21 => "bend_domain",
+                    _ => "transform_domain",
+                };
+                let domain_point = format!("p{}", index + 1);
+                writeln!(source, "    let {domain_point} = {function}({point}, {index}u);").ok()?;
+                point_stack.push(domain_point);
+                scope_stack.push(index);
+            },
+            csg_buffer::SCOPE_END_ID => {
+                // back to the point the scope was opened with
+                point_stack.pop()?;
+                let scope = scope_stack.pop()?;
+                let outer_point = point_stack.last()?;
+                let inner = sdf_stack.pop()?;
+                let sdf = format!("d{index}");
+                match gradient {
+                    false => writeln!(source, "    let {sdf} = {inner} / scope_stretch({outer_point}, {inner}, {index}u);"),
+                    true => writeln!(
+                        source,
+                        "    let {sdf} = vec4(scope_gradient({outer_point}, {inner}.xyz, {scope}u), {inner}.w) / scope_stretch({outer_point}, {inner}.w, {index}u);",
+                    ),
+                }.ok()?;
+                sdf_stack.push(sdf);
+            },
+            _ => return None,
+        }
+    }
+
+    // a valid tree leaves a single value
+    if sdf_stack.len() != 1 || point_stack.len() != 1 {
+        return None;
+    }
+    writeln!(source, "    return {};\n}}", sdf_stack[0]).ok()?;
+
+    Some(source)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::sdf::Primitive;
+
+    use super::*;
+    use super::csg_buffer::SCOPE_END_ID;
+
+    #[test]
+    fn domain_scope_evaluates_its_tree_at_the_changed_point() {
+        // union of a sphere and a torus, repeated
+        let source = generate_scene_sdf(&[17, 0, 6, 3, SCOPE_END_ID]).unwrap();
+        assert_eq!(source, "\
+fn scene_sdf(at: vec3<f32>) -> f32 {
+    let p0 = at;
+    let p1 = repeat_domain(p0, 0u);
+    let d1 = sphere_sdf(p1, 1u);
+    let d2 = torus_sdf(p1, 2u);
+    let d3 = min(d1, d2);
+    let d4 = d3 / scope_stretch(p0, d3, 4u);
+    return d4;
+}
+
+fn scene_sdf_gradient(at: vec3<f32>) -> vec4<f32> {
+    let p0 = at;
+    let p1 = repeat_domain(p0, 0u);
+    let d1 = primitive_gradient(p1, 1u);
+    let d2 = primitive_gradient(p1, 2u);
+    let d3 = select(d2, d1, d1.w < d2.w);
+    let d4 = vec4(scope_gradient(p0, d3.xyz, 0u), d3.w) / scope_stretch(p0, d3.w, 4u);
+    return d4;
+}
+");
+    }
+
+    #[test]
+    fn unknown_nodes_are_left_to_the_interpreter() {
+        // 2 is not a node id of the raymarcher
+        assert!(generate_scene_sdf(&[0, 2, 3]).is_none());
+    }
+
+    #[test]
+    fn invalid_trees_are_not_compiled() {
+        // operations missing operands
+        assert!(generate_scene_sdf(&[0, 3]).is_none());
+        assert!(generate_scene_sdf(&[SCOPE_END_ID]).is_none());
+        // scope never closed
+        assert!(generate_scene_sdf(&[17, 0]).is_none());
+        // more than one value left
+        assert!(generate_scene_sdf(&[0, 0]).is_none());
+        assert!(generate_scene_sdf(&[]).is_none());
+    }
+
+    #[test]
+    fn generated_source_completes_the_raymarcher() {
+        let offset = glam::Vec3::ZERO;
+        let rotation = glam::Quat::IDENTITY;
+        // every primitive, operation and domain operation, and differences with their operands both ways
+        let sdf = Sdf::from(csg::csg!(
+            csg::BinOp::Diff => {
+                csg::Primitive::sphere(0.3)
+            } {
+                csg::Primitive::Cube { offset, rotation, size: glam::Vec3::splat(0.2) }
+            }
+        ))
+            .union(Primitive::Torus { offset, rotation, major_radius: 0.3, minor_radius: 0.1 })
+            .inter(Primitive::Cylinder { offset, rotation, radius: 0.2, half_height: 0.5 })
+            .smooth_union(Primitive::Capsule { offset, rotation, radius: 0.1, half_height: 0.2 }, 0.05)
+            .smooth_inter(Primitive::Cone { offset, rotation, radius: 0.2, half_height: 0.3 }, 0.05)
+            .smooth_diff(Primitive::Plane { normal: glam::Vec3::Y, distance: -0.3 }, 0.05)
+            .diff(Primitive::Plane { normal: glam::Vec3::X, distance: 0.5 })
+            .union(Sdf::from(Primitive::Plane { normal: glam::Vec3::Z, distance: 0.1 }).smooth_diff(Primitive::Plane { normal: glam::Vec3::Y, distance: 0.2 }, 0.05))
+            .repeated(glam::Vec3::new(1.0, 0.0, 1.0))
+            .union(Sdf::from(Primitive::Ellipsoid { offset, rotation, radii: glam::Vec3::new(0.3, 0.2, 0.1) }).mirrored(true, false, true))
+            .union(Sdf::from(Primitive::RoundedBox { offset, rotation, size: glam::Vec3::splat(0.2), radius: 0.05 }).twisted(1.0))
+            .union(Sdf::from(Primitive::HexPrism { offset, rotation, radius: 0.1, half_height: 0.2 }).bent(0.5))
+            .repeated_limited(glam::Vec3::splat(2.0), glam::Vec3::ONE)
+            .transformed(glam::Vec3::X, rotation, glam::Vec3::new(1.0, 2.0, 1.0));
+        let ids = csg_buffer::encoded_ids(&sdf);
+        assert!(ids.contains(&5) && ids.contains(&23) && ids.contains(&16) && ids.contains(&24));
+        let compiled = CompiledSdf::new(&sdf).expect("all the nodes can be compiled");
+
+        // the same way the renderer builds the raymarcher shader
+        let shader = format!("{}\n{}", include_str!("../../../shaders/raymarcher.wgsl"), compiled.source());
+        let module = match naga::front::wgsl::parse_str(&shader) {
+            Ok(module) => module,
+            Err(error) => panic!("{}", error.emit_to_string(&shader)),
+        };
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::empty())
+            .validate(&module)
+            .unwrap();
+    }
+}
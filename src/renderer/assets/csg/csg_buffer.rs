@@ -2,28 +2,30 @@ use wgpu::util::DeviceExt;
 
 use crate::renderer::has_bind_group_layout::HasBindGroupLayout;
 
+use crate::sdf::{DomainOp, Operation, Primitive, Sdf};
+
+use super::bounding_box::BoundingBox;
+
 
 /// WGPU buffer that contains a csg object.
 pub(crate) struct CsgBuffer {
     buffer_size: usize,
     buffer: wgpu::Buffer,
     size_buffer: wgpu::Buffer,
+    bounding_box_buffer: wgpu::Buffer,
     bind_group: wgpu::BindGroup,
 }
 
 impl CsgBuffer {
 
-    pub(crate) fn new(device: &wgpu::Device, csg: &csg::CSG) -> CsgBuffer {
+    pub(crate) fn new(device: &wgpu::Device, sdf: &Sdf, bounding_box: &BoundingBox) -> CsgBuffer {
 
-        let mut buffer = Vec::with_capacity(csg.node_count() * CSG_NODE_GPU_SIZE);
+        let mut nodes = Vec::new();
+        encode_sdf(sdf, &mut nodes);
+        let node_count = nodes.len();
+        let buffer: Vec<u8> = nodes.into_iter().flatten().collect();
 
-        // the gpu needs the csg vec in reverse to compute sdf
-        for node in csg.nodes().rev() {
-            let buffered_node = to_gpu_data(node);
-            buffer.extend_from_slice(&buffered_node);
-        }
-
-        let node_count_u32: u32 = csg.node_count().try_into().expect("Unable to convert csg tree size to u32 !");
+        let node_count_u32: u32 = node_count.try_into().expect("Unable to convert csg tree size to u32 !");
 
         let buffer_init = wgpu::util::BufferInitDescriptor {
             label: Some("CSG Object data buffer"),
@@ -35,43 +37,35 @@ impl CsgBuffer {
             contents: &node_count_u32.to_ne_bytes(),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         };
+        let bounding_box_buffer_init = wgpu::util::BufferInitDescriptor {
+            label: Some("CSG Object bounding box buffer"),
+            contents: &bounding_box.to_gpu_data(),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        };
 
         let buffer = device.create_buffer_init(&buffer_init);
         let size_buffer = device.create_buffer_init(&size_buffer_init);
+        let bounding_box_buffer = device.create_buffer_init(&bounding_box_buffer_init);
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &Self::bind_group_layout(&device),
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: buffer.as_entire_binding(),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: size_buffer.as_entire_binding(),
-                },
-            ],
-            label: Some("csg buffer bind group"),
-        });
+        let bind_group = create_bind_group(device, &buffer, &size_buffer, &bounding_box_buffer);
         
         CsgBuffer {
-            buffer_size: csg.node_count(),
+            buffer_size: node_count,
             buffer,
             size_buffer,
+            bounding_box_buffer,
             bind_group,
         }
     }
 
-    pub(crate) fn update_csg(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, csg: &csg::CSG) {
+    pub(crate) fn update_sdf(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, sdf: &Sdf, bounding_box: &BoundingBox) {
         
-        let mut buffer = Vec::with_capacity(csg.node_count() * CSG_NODE_GPU_SIZE);
+        let mut nodes = Vec::new();
+        encode_sdf(sdf, &mut nodes);
+        let node_count = nodes.len();
+        let buffer: Vec<u8> = nodes.into_iter().flatten().collect();
 
-        for node in csg.nodes().rev() {
-            let buffered_node = to_gpu_data(node);
-            buffer.extend_from_slice(&buffered_node);
-        }
-
-        if self.buffer_size >= csg.node_count() {
+        if self.buffer_size < node_count {
             // need to reallocate the csg buffer
             let buffer_init = wgpu::util::BufferInitDescriptor {
                 label: Some("CSG Object data buffer"),
@@ -79,14 +73,17 @@ impl CsgBuffer {
                 usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
             };
             self.buffer = device.create_buffer_init(&buffer_init);
-            self.buffer_size = csg.node_count();
+            self.buffer_size = node_count;
+            // the bind group was pointing to the old buffer
+            self.bind_group = create_bind_group(device, &self.buffer, &self.size_buffer, &self.bounding_box_buffer);
         }
         else {
             queue.write_buffer(&self.buffer, 0, &buffer);
         }
 
-        let node_count_u32: u32 = csg.node_count().try_into().expect("Unable to convert csg tree size to u32 !");
+        let node_count_u32: u32 = node_count.try_into().expect("Unable to convert csg tree size to u32 !");
         queue.write_buffer(&self.size_buffer, 0, &node_count_u32.to_ne_bytes());
+        queue.write_buffer(&self.bounding_box_buffer, 0, &bounding_box.to_gpu_data());
     } 
 
     pub(crate) fn bind_group(&self) -> &wgpu::BindGroup {
@@ -94,6 +91,27 @@ impl CsgBuffer {
     }
 }
 
+fn create_bind_group(device: &wgpu::Device, buffer: &wgpu::Buffer, size_buffer: &wgpu::Buffer, bounding_box_buffer: &wgpu::Buffer) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &CsgBuffer::bind_group_layout(device),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: size_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: bounding_box_buffer.as_entire_binding(),
+            },
+        ],
+        label: Some("csg buffer bind group"),
+    })
+}
+
 impl HasBindGroupLayout for CsgBuffer {
     fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
         device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -118,6 +136,17 @@ impl HasBindGroupLayout for CsgBuffer {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    // the vertex shader scales the proxy cube to the bounding box
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
             ],
             label: Some("csg buffer bind group layout"),
         })
@@ -129,51 +158,512 @@ impl HasBindGroupLayout for CsgBuffer {
 /// Should be a multiple of 16 for alignment 
 const CSG_NODE_GPU_SIZE: usize = 4 + 4 * 11; //std::mem::size_of::<csg::csg_node::Node>(); 
 
-fn to_gpu_data(node: &csg::node::CsgNode) -> [u8; CSG_NODE_GPU_SIZE] {
-    let mut result = [0u8; CSG_NODE_GPU_SIZE];
+/// Gpu id of the node closing the scope of a domain operation.
+/// This is not a csg node, so it is out of the range of the csg ids.
+pub(super) const SCOPE_END_ID: u32 = 255;
+
+/// Height of the value and point stacks of the interpreters, the deepest trees they can evaluate.
+pub(crate) const MAX_STACK_DEPTH: usize = 8;
+
+/// Encode the tree the way the gpu evaluates it: operations come after their operands.
+/// Domain operations change the point their subtree is evaluated at, so they are split in two nodes:
+/// one before the subtree that pushes the transformed point, and one after it that pops it.
+/// The operand needing the most stack is encoded first (Sethi–Ullman order), so chains of operations
+/// only need two values on the stack whichever side they lean on.
+/// Returns the height of the value stack the tree needs.
+fn encode_sdf(sdf: &Sdf, nodes: &mut Vec<[u8; CSG_NODE_GPU_SIZE]>) -> usize {
+    match sdf {
+        Sdf::Csg(csg) => {
+            let prefix: Vec<_> = csg.nodes().collect();
+            encode_csg(&prefix, nodes).1
+        },
+        Sdf::Primitive(primitive) => {
+            let mut node = gpu_node(primitive_id(primitive));
+            load_primitive_data(primitive, &mut node[4..]);
+            nodes.push(node);
+            1
+        },
+        Sdf::Operation(operation, first, second) => {
+            let mut first_nodes = Vec::new();
+            let first_depth = encode_sdf(first, &mut first_nodes);
+            let mut second_nodes = Vec::new();
+            let second_depth = encode_sdf(second, &mut second_nodes);
+            let (swapped, depth) = push_operands(nodes, (first_nodes, first_depth), (second_nodes, second_depth));
+
+            let id = operation_id(operation);
+            let mut node = gpu_node(if swapped { swapped_id(id) } else { id });
+            load_operation_data(operation, &mut node[4..]);
+            nodes.push(node);
+            depth
+        },
+        Sdf::Domain(operation, inner) => {
+            let mut node = gpu_node(domain_id(operation));
+            load_domain_data(operation, &mut node[4..]);
+            nodes.push(node);
+            let depth = encode_sdf(inner, nodes);
+            nodes.push(scope_end_node(domain_stretch(operation)));
+            depth
+        },
+    }
+}
+
+/// Encode the subtree at the start of the csg nodes, which are stored in prefix order.
+/// Returns the number of nodes of the subtree and the height of the value stack it needs.
+fn encode_csg(prefix: &[&csg::node::CsgNode], nodes: &mut Vec<[u8; CSG_NODE_GPU_SIZE]>) -> (usize, usize) {
+    let Some((&node, operands)) = prefix.split_first() else {
+        return (0, 0);
+    };
+    if let csg::node::CsgNode::Primitive(_) = node {
+        nodes.push(to_gpu_data(node));
+        return (1, 1);
+    }
+
+    // every other node is an operation between the two subtrees that follow it
+    let mut first_nodes = Vec::new();
+    let (first_count, first_depth) = encode_csg(operands, &mut first_nodes);
+    let mut second_nodes = Vec::new();
+    let (second_count, second_depth) = encode_csg(&operands[first_count..], &mut second_nodes);
+    let (swapped, depth) = push_operands(nodes, (first_nodes, first_depth), (second_nodes, second_depth));
+
+    let mut node = to_gpu_data(node);
+    if swapped {
+        let id = swapped_id(u32::from_ne_bytes([node[0], node[1], node[2], node[3]]));
+        node[..4].copy_from_slice(&id.to_ne_bytes());
+    }
+    nodes.push(node);
+    (1 + first_count + second_count, depth)
+}
+
+/// Push the encoded operands of an operation, with the height of the value stack they need.
+/// The first operand normally ends up on top of the stack, as with csg trees, but it goes first when it
+/// needs a deeper stack. Returns whether the operands are swapped, and the height of the stack they need together.
+fn push_operands(
+    nodes: &mut Vec<[u8; CSG_NODE_GPU_SIZE]>,
+    (first, first_depth): (Vec<[u8; CSG_NODE_GPU_SIZE]>, usize),
+    (second, second_depth): (Vec<[u8; CSG_NODE_GPU_SIZE]>, usize),
+) -> (bool, usize) {
+    // the value of the operand encoded first stays on the stack while the other one is evaluated
+    if first_depth > second_depth {
+        nodes.extend(first);
+        nodes.extend(second);
+        (true, first_depth.max(second_depth + 1))
+    }
+    else {
+        nodes.extend(second);
+        nodes.extend(first);
+        (false, second_depth.max(first_depth + 1))
+    }
+}
+
+/// Id of the operation with the first operand below the second one on the stack.
+/// Only differences depend on the order of their operands.
+fn swapped_id(id: u32) -> u32 {
+    match id {
+        5 => 23,
+        16 => 24,
+        id => id,
+    }
+}
+
+/// Height of the value and point stacks the interpreters need to evaluate a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StackDepths {
+    pub(crate) values: usize,
+    pub(crate) points: usize,
+}
+
+impl StackDepths {
+    /// Follow the stacks of the interpreters through the encoded nodes.
+    pub(super) fn of(node_ids: &[u32]) -> StackDepths {
+        // current height of the stacks, and the highest they got
+        let (mut values, mut points) = (0usize, 0usize);
+        let mut depths = StackDepths { values: 0, points: 0 };
+        for &id in node_ids {
+            match id {
+                0 | 1 | 6..=13 => values += 1,
+                3..=5 | 14..=16 | 23 | 24 => values = values.saturating_sub(1),
+                17..=22 => points += 1,
+                SCOPE_END_ID => points = points.saturating_sub(1),
+                _ => {},
+            }
+            depths.values = depths.values.max(values);
+            depths.points = depths.points.max(points);
+        }
+        depths
+    }
+
+    pub(crate) fn fits(&self) -> bool {
+        self.values <= MAX_STACK_DEPTH && self.points <= MAX_STACK_DEPTH
+    }
+}
+
+/// Gpu ids of the encoded nodes, in evaluation order.
+pub(super) fn encoded_ids(sdf: &Sdf) -> Vec<u32> {
+    let mut nodes = Vec::new();
+    encode_sdf(sdf, &mut nodes);
+    nodes.iter()
+        .map(|node| u32::from_ne_bytes([node[0], node[1], node[2], node[3]]))
+        .collect()
+}
 
-    for (i, byte) in node.id().to_ne_bytes().into_iter().enumerate() {
-        result[i] = byte
+/// Gpu ids of the primitives and operations, after the ones of the csg crate.
+fn primitive_id(primitive: &Primitive) -> u32 {
+    match primitive {
+        Primitive::Torus { .. } => 6,
+        Primitive::Cylinder { .. } => 7,
+        Primitive::Capsule { .. } => 8,
+        Primitive::Cone { .. } => 9,
+        Primitive::Plane { .. } => 10,
+        Primitive::Ellipsoid { .. } => 11,
+        Primitive::RoundedBox { .. } => 12,
+        Primitive::HexPrism { .. } => 13,
     }
+}
+
+/// Hard operations share the ids of the csg crate, the raymarcher evaluates them the same way.
+fn operation_id(operation: &Operation) -> u32 {
+    match operation {
+        Operation::Union => 3,
+        Operation::Inter => 4,
+        Operation::Diff => 5,
+        Operation::SmoothUnion { .. } => 14,
+        Operation::SmoothInter { .. } => 15,
+        Operation::SmoothDiff { .. } => 16,
+    }
+}
+
+fn domain_id(operation: &DomainOp) -> u32 {
+    match operation {
+        DomainOp::Repeat { .. } => 17,
+        DomainOp::RepeatLimited { .. } => 18,
+        DomainOp::Mirror { .. } => 19,
+        DomainOp::Twist { .. } => 20,
+        DomainOp::Bend { .. } => 21,
+        DomainOp::Transform { .. } => 22,
+    }
+}
+
+/// Node with the id, and its data left to zero.
+fn gpu_node(id: u32) -> [u8; CSG_NODE_GPU_SIZE] {
+    let mut result = [0u8; CSG_NODE_GPU_SIZE];
+    result[..4].copy_from_slice(&id.to_ne_bytes());
+    result
+}
+
+fn to_gpu_data(node: &csg::node::CsgNode) -> [u8; CSG_NODE_GPU_SIZE] {
+    let mut result = gpu_node(node.id());
 
     match node {
         // put data when necessary
-        csg::node::CsgNode::Primitive(primitive) => load_primitive_data(primitive, &mut result[4..]),
+        csg::node::CsgNode::Primitive(primitive) => load_csg_primitive_data(primitive, &mut result[4..]),
         _ => { /* no data to pass */ }
     }
 
     result
 }
 
-fn load_primitive_data(primitive: &csg::Primitive, buffer: &mut [u8]) {
+fn load_operation_data(operation: &Operation, buffer: &mut [u8]) {
+    match operation {
+        Operation::SmoothUnion { radius }
+        | Operation::SmoothInter { radius }
+        | Operation::SmoothDiff { radius } => write_data(buffer, &[*radius]),
+        Operation::Union | Operation::Inter | Operation::Diff => { /* no data to pass */ }
+    }
+}
+
+fn load_domain_data(operation: &DomainOp, buffer: &mut [u8]) {
+    match operation {
+        // axes with a period of zero are not repeated
+        DomainOp::Repeat { period } => write_data(buffer, &[period.x, period.y, period.z]),
+        // count copies on each side of the original
+        DomainOp::RepeatLimited { period, count } => {
+            write_data(buffer, &[period.x, period.y, period.z, count.x, count.y, count.z]);
+        },
+        DomainOp::Mirror { x, y, z } => {
+            let mask = |mirrored: bool| if mirrored { 1.0 } else { 0.0 };
+            write_data(buffer, &[mask(*x), mask(*y), mask(*z)]);
+        },
+        // radians per unit along y, and along x
+        DomainOp::Twist { rate } | DomainOp::Bend { rate } => write_data(buffer, &[*rate]),
+        DomainOp::Transform { translation, rotation, scale } => {
+            write_placed_data(buffer, translation, rotation, &[scale.x, scale.y, scale.z]);
+        },
+    }
+}
+
+/// How much the operation stretches space, so the distance of the subtree can be scaled back:
+/// stretch rate, and the plane in which the distance to the axis makes it grow,
+/// 0 for a constant stretch, 1 for the xz plane (twist around y), 2 for the xy plane (bend around z).
+fn domain_stretch(operation: &DomainOp) -> [f32; 2] {
+    match operation {
+        DomainOp::Twist { rate } => [rate.abs(), 1.0],
+        DomainOp::Bend { rate } => [rate.abs(), 2.0],
+        // shrinking the subtree brings points closer than their distance in the subtree
+        DomainOp::Transform { scale, .. } => [1.0 / scale.abs().min_element().max(f32::EPSILON), 0.0],
+        DomainOp::Repeat { .. }
+        | DomainOp::RepeatLimited { .. }
+        | DomainOp::Mirror { .. } => [1.0, 0.0],
+    }
+}
+
+/// Node closing the scope of a domain operation, with the stretch of the operation.
+fn scope_end_node(stretch: [f32; 2]) -> [u8; CSG_NODE_GPU_SIZE] {
+    let mut result = gpu_node(SCOPE_END_ID);
+    write_data(&mut result[4..], &stretch);
+    result
+}
+
+fn load_csg_primitive_data(primitive: &csg::Primitive, buffer: &mut [u8]) {
     match primitive {
         csg::Primitive::Sphere { radius, offset } => {
-            let bytes = [
-                offset.x.to_ne_bytes(),
-                offset.y.to_ne_bytes(),
-                offset.z.to_ne_bytes(),
-                radius.to_ne_bytes(),
-            ];
-            for (i, byte) in bytes.into_iter().flatten().enumerate() {
-                buffer[i] = byte;
-            }
+            write_data(buffer, &[offset.x, offset.y, offset.z, *radius]);
         }
         csg::Primitive::Cube { offset, rotation, size } => {
-            let bytes = [
-                offset.x.to_ne_bytes(),
-                offset.y.to_ne_bytes(),
-                offset.z.to_ne_bytes(),
-                rotation.x.to_ne_bytes(),
-                rotation.y.to_ne_bytes(),
-                rotation.z.to_ne_bytes(),
-                rotation.w.to_ne_bytes(),
-                size.x.to_ne_bytes(),
-                size.y.to_ne_bytes(),
-                size.z.to_ne_bytes(),
-            ];
-            for (i, byte) in bytes.into_iter().flatten().enumerate() {
-                buffer[i] = byte;
+            write_placed_data(buffer, offset, rotation, &[size.x, size.y, size.z]);
+        }
+    }
+}
+
+/// Primitives other than planes are laid out the same way as cubes, and transform nodes:
+/// offset (3 floats), rotation quaternion (4 floats), then up to 4 floats of shape parameters.
+fn load_primitive_data(primitive: &Primitive, buffer: &mut [u8]) {
+    match primitive {
+        Primitive::Torus { offset, rotation, major_radius, minor_radius } => {
+            write_placed_data(buffer, offset, rotation, &[*major_radius, *minor_radius]);
+        }
+        Primitive::Cylinder { offset, rotation, radius, half_height } => {
+            write_placed_data(buffer, offset, rotation, &[*radius, *half_height]);
+        }
+        Primitive::Capsule { offset, rotation, radius, half_height } => {
+            write_placed_data(buffer, offset, rotation, &[*radius, *half_height]);
+        }
+        Primitive::Cone { offset, rotation, radius, half_height } => {
+            write_placed_data(buffer, offset, rotation, &[*radius, *half_height]);
+        }
+        Primitive::Plane { normal, distance } => {
+            // the sdf needs a unit normal, scale the distance along with it
+            let length = normal.length();
+            let (normal, distance) = match length > 0.0 {
+                true => (*normal / length, *distance / length),
+                false => (glam::Vec3::Y, *distance),
+            };
+            write_data(buffer, &[normal.x, normal.y, normal.z, distance]);
+        }
+        Primitive::Ellipsoid { offset, rotation, radii } => {
+            write_placed_data(buffer, offset, rotation, &[radii.x, radii.y, radii.z]);
+        }
+        Primitive::RoundedBox { offset, rotation, size, radius } => {
+            write_placed_data(buffer, offset, rotation, &[size.x, size.y, size.z, *radius]);
+        }
+        Primitive::HexPrism { offset, rotation, radius, half_height } => {
+            write_placed_data(buffer, offset, rotation, &[*radius, *half_height]);
+        }
+    }
+}
+
+fn write_placed_data(buffer: &mut [u8], offset: &glam::Vec3, rotation: &glam::Quat, parameters: &[f32]) {
+    write_data(buffer, &[offset.x, offset.y, offset.z, rotation.x, rotation.y, rotation.z, rotation.w]);
+    write_data(&mut buffer[7 * 4..], parameters);
+}
+
+fn write_data(buffer: &mut [u8], data: &[f32]) {
+    for (i, byte) in data.iter().flat_map(|value| value.to_ne_bytes()).enumerate() {
+        buffer[i] = byte;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::world::components::transform::Transform;
+
+    use super::*;
+
+    /// Data of the node, as the raymarcher reads it.
+    fn node_data(primitive: &csg::Primitive) -> [f32; 11] {
+        let mut bytes = [0u8; CSG_NODE_GPU_SIZE - 4];
+        load_csg_primitive_data(primitive, &mut bytes);
+        let mut data = [0.0; 11];
+        for (value, bytes) in data.iter_mut().zip(bytes.chunks(4)) {
+            *value = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        data
+    }
+
+    /// `quat_rotate` of the raymarcher.
+    fn quat_rotate(q: glam::Vec4, v: glam::Vec3) -> glam::Vec3 {
+        let t = 2.0 * q.truncate().cross(v);
+        v + q.w * t + q.truncate().cross(t)
+    }
+
+    /// `cube_sdf` of the raymarcher, with `primitive_space` bringing the point back with the inverse rotation.
+    fn shader_cube_sdf(at: glam::Vec3, data: &[f32; 11]) -> f32 {
+        let position = glam::Vec3::new(data[0], data[1], data[2]);
+        let rotation = glam::Vec4::new(data[3], data[4], data[5], data[6]);
+        let scale = glam::Vec3::new(data[7], data[8], data[9]);
+        let conjugate = glam::Vec4::new(-rotation.x, -rotation.y, -rotation.z, rotation.w);
+        let q = quat_rotate(conjugate, at - position).abs() - scale;
+        q.max(glam::Vec3::ZERO).length() + q.max_element().min(0.0)
+    }
+
+    fn aligned_cube_sdf(at: glam::Vec3, size: glam::Vec3) -> f32 {
+        let q = at.abs() - size;
+        q.max(glam::Vec3::ZERO).length() + q.max_element().min(0.0)
+    }
+
+    #[test]
+    fn rotated_cube_matches_rotated_transform() {
+        let offset = glam::Vec3::new(0.1, 0.05, -0.2);
+        let size = glam::Vec3::new(0.25, 0.15, 0.1);
+        let rotations = [
+            glam::Quat::from_euler(glam::EulerRot::YXZ, 0.6, 0.4, 0.2),
+            glam::Quat::from_rotation_z(std::f32::consts::FRAC_PI_2),
+            glam::Quat::from_axis_angle(glam::Vec3::new(1.0, -1.0, 2.0).normalize(), 2.8),
+        ];
+
+        for rotation in rotations {
+            let data = node_data(&csg::Primitive::Cube { offset, rotation, size });
+            let world_to_object = Transform::origin().at(offset).rotated(rotation).local_matrix().inverse();
+
+            for x in -4..=4 {
+                for y in -4..=4 {
+                    for z in -4..=4 {
+                        let at = glam::Vec3::new(x as f32, y as f32, z as f32) * 0.1;
+                        let expected = aligned_cube_sdf(world_to_object.transform_point3(at), size);
+                        let sdf = shader_cube_sdf(at, &data);
+                        assert!((sdf - expected).abs() < 1.0e-5, "at {at}: {sdf} != {expected}");
+                    }
+                }
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn operations_put_their_first_operand_on_top() {
+        let torus = Primitive::Torus {
+            offset: glam::Vec3::ZERO, rotation: glam::Quat::IDENTITY, major_radius: 0.3, minor_radius: 0.1,
+        };
+        let cylinder = Primitive::Cylinder {
+            offset: glam::Vec3::ZERO, rotation: glam::Quat::IDENTITY, radius: 0.2, half_height: 0.5,
+        };
+        let capsule = Primitive::Capsule {
+            offset: glam::Vec3::ZERO, rotation: glam::Quat::IDENTITY, radius: 0.1, half_height: 0.2,
+        };
+
+        // the raymarcher carves the value below on the stack out of the top one
+        let sdf = Sdf::from(torus).diff(Sdf::from(cylinder).union(capsule));
+        assert_eq!(encoded_ids(&sdf), vec![8, 7, 3, 6, 5]);
+    }
+
+    #[test]
+    fn domain_operations_open_and_close_a_scope_around_their_tree() {
+        let torus = Primitive::Torus {
+            offset: glam::Vec3::ZERO, rotation: glam::Quat::IDENTITY, major_radius: 0.3, minor_radius: 0.1,
+        };
+        let plane = Primitive::Plane { normal: glam::Vec3::Y, distance: 0.0 };
+
+        let sdf = Sdf::from(torus).twisted(2.0).repeated(glam::Vec3::new(1.0, 0.0, 1.0)).union(plane);
+        assert_eq!(encoded_ids(&sdf), vec![10, 17, 20, 6, SCOPE_END_ID, SCOPE_END_ID, 3]);
+    }
+
+    #[test]
+    fn transforms_scale_the_distance_back_when_closing_their_scope() {
+        let ellipsoid = Primitive::Ellipsoid {
+            offset: glam::Vec3::ZERO, rotation: glam::Quat::IDENTITY, radii: glam::Vec3::new(0.3, 0.2, 0.1),
+        };
+        let sdf = Sdf::from(ellipsoid).transformed(glam::Vec3::X, glam::Quat::IDENTITY, glam::Vec3::new(2.0, 0.5, 1.0));
+        assert_eq!(encoded_ids(&sdf), vec![22, 11, SCOPE_END_ID]);
+
+        // the subtree shrinks by 2 along y, its distances are twice too far
+        let scope_end = scope_end_node(domain_stretch(&DomainOp::Transform {
+            translation: glam::Vec3::X, rotation: glam::Quat::IDENTITY, scale: glam::Vec3::new(2.0, 0.5, 1.0),
+        }));
+        assert_eq!(f32::from_ne_bytes([scope_end[4], scope_end[5], scope_end[6], scope_end[7]]), 2.0);
+    }
+
+    fn leaf(index: usize) -> Sdf {
+        Sdf::from(csg::csg!(csg::Primitive::sphere(0.1).at(glam::Vec3::X * index as f32)))
+    }
+
+    /// Balanced union of 2^height leaves.
+    fn balanced_union(height: u32) -> Sdf {
+        match height {
+            0 => leaf(0),
+            _ => balanced_union(height - 1).union(balanced_union(height - 1)),
+        }
+    }
+
+    #[test]
+    fn operation_chains_need_two_values_on_the_stack() {
+        // a union chain leaning on its first operand, the way the builder methods grow trees
+        let left_chain = (1..16).fold(leaf(0), |chain, index| chain.union(leaf(index)));
+        assert_eq!(StackDepths::of(&encoded_ids(&left_chain)), StackDepths { values: 2, points: 0 });
+
+        let right_chain = (1..16).fold(leaf(0), |chain, index| leaf(index).union(chain));
+        assert_eq!(StackDepths::of(&encoded_ids(&right_chain)), StackDepths { values: 2, points: 0 });
+    }
+
+    #[test]
+    fn csg_trees_are_encoded_in_the_same_order() {
+        // sphere - (sphere | sphere), in prefix order: the deeper second operand goes first
+        let csg = csg::csg!(
+            csg::BinOp::Diff => {
+                csg::Primitive::sphere(0.3)
+            } {
+                csg::BinOp::Union => {
+                    csg::Primitive::sphere(0.1)
+                } {
+                    csg::Primitive::sphere(0.2)
+                }
+            }
+        );
+        assert_eq!(encoded_ids(&Sdf::from(csg)), vec![0, 0, 3, 0, 5]);
+
+        // (sphere | sphere) - sphere: the deeper first operand goes first, below the second one
+        let csg = csg::csg!(
+            csg::BinOp::Diff => {
+                csg::BinOp::Union => {
+                    csg::Primitive::sphere(0.1)
+                } {
+                    csg::Primitive::sphere(0.2)
+                }
+            } {
+                csg::Primitive::sphere(0.3)
+            }
+        );
+        let ids = encoded_ids(&Sdf::from(csg));
+        assert_eq!(ids, vec![0, 0, 3, 0, 23]);
+        assert_eq!(StackDepths::of(&ids).values, 2);
+    }
+
+    #[test]
+    fn differences_with_a_deeper_first_operand_swap_their_operands() {
+        let torus = Primitive::Torus {
+            offset: glam::Vec3::ZERO, rotation: glam::Quat::IDENTITY, major_radius: 0.3, minor_radius: 0.1,
+        };
+        let cylinder = Primitive::Cylinder {
+            offset: glam::Vec3::ZERO, rotation: glam::Quat::IDENTITY, radius: 0.2, half_height: 0.5,
+        };
+        let capsule = Primitive::Capsule {
+            offset: glam::Vec3::ZERO, rotation: glam::Quat::IDENTITY, radius: 0.1, half_height: 0.2,
+        };
+
+        // the first operand is evaluated first and stays below the second one
+        let sdf = Sdf::from(cylinder).union(capsule).diff(torus);
+        assert_eq!(encoded_ids(&sdf), vec![8, 7, 3, 6, 23]);
+        let sdf = Sdf::from(cylinder).union(capsule).smooth_diff(torus, 0.1);
+        assert_eq!(encoded_ids(&sdf), vec![8, 7, 3, 6, 24]);
+    }
+
+    #[test]
+    fn stack_depths_follow_the_height_of_balanced_trees() {
+        // a balanced tree of 2^n leaves needs n + 1 values
+        assert_eq!(StackDepths::of(&encoded_ids(&balanced_union(4))).values, 5);
+        assert!(StackDepths::of(&encoded_ids(&balanced_union(7))).fits());
+        assert!(!StackDepths::of(&encoded_ids(&balanced_union(8))).fits());
+
+        let nested = (0..8).fold(leaf(0), |sdf, _| sdf.mirrored(true, false, false));
+        assert_eq!(StackDepths::of(&encoded_ids(&nested)), StackDepths { values: 1, points: 8 });
+        assert!(!StackDepths::of(&encoded_ids(&nested.twisted(1.0))).fits());
+    }
+}
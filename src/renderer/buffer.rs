@@ -27,7 +27,6 @@ pub(crate) trait BufferElem : bytemuck::Pod {
 /// this assumes the data T can be transparently sent to the gpu.
 pub(crate) struct Buffer<T: BufferElem, const TYPE_ARRAY: bool> {
     marker: std::marker::PhantomData<T>,
-    #[allow(unused)]
     buffer_size: u64, // not used if !TYPE_ARRAY
     buffer: wgpu::Buffer,
     bind_group: wgpu::BindGroup,
@@ -46,7 +45,7 @@ impl<T: BufferElem> Buffer<T, false> {
         let buffer_init = wgpu::util::BufferInitDescriptor {
             label,
             contents: t.to_bytes(),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            usage: buffer_usages::<T>(),
         };
         
         let buffer = device.create_buffer_init(&buffer_init);
@@ -85,42 +84,10 @@ impl<T: BufferElem> Buffer<T, false> {
 
 impl<T: BufferElem> Buffer<T, true> {
     pub(crate) fn empty(device: &wgpu::Device) -> Buffer<T, true> {
-
-        #[cfg(debug_assertions)]
-        let label = format!("{:?} buffer init descriptor", T::LABEL);
-        #[cfg(debug_assertions)]
-        let label = Some(label.as_str());
-        #[cfg(not(debug_assertions))]
-        let label = Some("buffer init descriptor");
-
         let start_size = 16 * T::SIZE; // what is an optimal value ?
 
-        let buffer_desc = wgpu::BufferDescriptor {
-            label,
-            size: start_size,
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        };
-
-        let buffer = device.create_buffer(&buffer_desc);
-
-        #[cfg(debug_assertions)]
-        let label = format!("{:?} bind group", T::LABEL);
-        #[cfg(debug_assertions)]
-        let label = Some(label.as_str());
-        #[cfg(not(debug_assertions))]
-        let label = Some("bind group");
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &Self::bind_group_layout(device),
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: T::BINDING,
-                    resource: buffer.as_entire_binding(),
-                }
-            ],
-            label,
-        });
+        let buffer = create_array_buffer::<T>(device, start_size);
+        let bind_group = create_array_bind_group::<T>(device, &buffer);
 
         Buffer { 
             marker: Default::default(),
@@ -130,16 +97,91 @@ impl<T: BufferElem> Buffer<T, true> {
         }
     }
 
-    pub(crate) fn update_elem(&mut self, queue: &wgpu::Queue, at: u64, t: T) {
+    /// Make sure the buffer can hold at least count elements.
+    /// Otherwise, a bigger buffer is allocated, the current content is copied into it and the bind group is rebuilt.
+    pub(crate) fn reserve(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, count: u64) {
+        let required_size = count * T::SIZE;
+        if required_size <= self.buffer_size {
+            return;
+        }
+
+        // grow geometrically, so adding elements one by one does not reallocate every time
+        let new_size = required_size.max(2 * self.buffer_size);
+        let new_buffer = create_array_buffer::<T>(device, new_size);
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("buffer grow encoder"),
+        });
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &new_buffer, 0, self.buffer_size);
+        queue.submit(std::iter::once(encoder.finish()));
+
+        self.bind_group = create_array_bind_group::<T>(device, &new_buffer);
+        self.buffer = new_buffer;
+        self.buffer_size = new_size;
+    }
+
+    /// Write the element at the given index. The buffer grows if needed.
+    pub(crate) fn update_elem(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, at: u64, t: T) {
+        self.reserve(device, queue, at + 1);
         queue.write_buffer(&self.buffer, at * T::SIZE, t.to_bytes())
     }
 }
 
+fn create_array_buffer<T: BufferElem>(device: &wgpu::Device, size: u64) -> wgpu::Buffer {
+    #[cfg(debug_assertions)]
+    let label = format!("{:?} buffer descriptor", T::LABEL);
+    #[cfg(debug_assertions)]
+    let label = Some(label.as_str());
+    #[cfg(not(debug_assertions))]
+    let label = Some("buffer descriptor");
+
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label,
+        size,
+        // copy src so we can move the content when growing
+        usage: buffer_usages::<T>() | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_array_bind_group<T: BufferElem>(device: &wgpu::Device, buffer: &wgpu::Buffer) -> wgpu::BindGroup {
+    #[cfg(debug_assertions)]
+    let label = format!("{:?} bind group", T::LABEL);
+    #[cfg(debug_assertions)]
+    let label = Some(label.as_str());
+    #[cfg(not(debug_assertions))]
+    let label = Some("bind group");
+
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &Buffer::<T, true>::bind_group_layout(device),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: T::BINDING,
+                resource: buffer.as_entire_binding(),
+            }
+        ],
+        label,
+    })
+}
+
 
 impl<T: BufferElem, const TYPE_ARRAY: bool> Buffer<T, TYPE_ARRAY> {
     pub(crate) fn bind_group(&self) -> &wgpu::BindGroup {
         &self.bind_group
     }
+
+    /// Binds the whole buffer, to put it in bind groups shared with other resources.
+    pub(crate) fn as_entire_binding(&self) -> wgpu::BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+}
+
+/// Usages of the buffer, depending on how T is bound.
+fn buffer_usages<T: BufferElem>() -> wgpu::BufferUsages {
+    match T::BINDING_TYPE {
+        wgpu::BindingType::Buffer { ty: wgpu::BufferBindingType::Storage { .. }, .. } => wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        _ => wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    }
 }
 
 impl<T: BufferElem, const TYPE_ARRAY: bool> HasBindGroupLayout for Buffer<T, TYPE_ARRAY> {
@@ -1,40 +1,90 @@
+mod cone_prepass;
+mod depth_texture;
+mod instance_buffer;
+mod instance_data;
+pub(crate) mod pass_timer;
+mod pipeline_cache;
+mod slot_allocator;
 mod texture;
 mod textures;
 // mod storage_buffer;
 
 use legion::IntoQuery;
+use self::cone_prepass::ConePrepass;
+use self::depth_texture::DepthTexture;
+use self::instance_buffer::InstanceBuffer;
+use self::instance_data::InstanceToGpu;
+use self::pass_timer::{PassTimer, PassTimings, TimedPass};
+use self::pipeline_cache::PipelineCache;
+use self::slot_allocator::SlotAllocator;
 use self::textures::{AlbedoTexture, NormalDepthTexture};
 
 use super::asset_manager::AssetManager;
 use super::assets::csg::CsgObjectAsset;
 use super::assets::csg::csg_buffer::CsgBuffer;
 use super::buffer::Buffer;
+use super::raymarch_settings::RaymarchSettings;
 use super::screen_resolution::ScreenResolution;
 use crate::world::camera::CameraToGpu;
 use crate::renderer::has_bind_group_layout::HasBindGroupLayout;
 use crate::world::components::csg_renderer::CsgRenderer;
-use crate::world::components::transform::{Transform, TransformToGpu};
+use crate::world::components::gpu_slot::GpuSlot;
+use crate::world::components::transform::GlobalTransform;
 
 
 
+/// Format of the targets the raymarching iterations are counted into.
+/// Counts are summed by blending, half floats hold them exactly up to 2048.
+pub(crate) const ITERATIONS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R16Float;
+
+/// Legion query returning the entities with a changed global transform or csg renderer.
+type ChangedInstancesQuery = Box<dyn FnMut(&legion::World) -> Vec<legion::Entity> + Send>;
+
+/// Range of instances in the instance buffer that share the same csg asset.
+struct AssetInstances {
+    asset_id: u64,
+    instances: std::ops::Range<u32>,
+}
+
 /// A deffered renderer.
 pub(crate) struct DeferredRenderer {
-    transform_buffer: Buffer<TransformToGpu, true>,
+    /// Transforms and settings of all entities, each at the slot of its entity.
+    instance_data_buffer: Buffer<InstanceToGpu, true>,
+    /// Entities whose global transform or csg renderer may have changed since the last upload.
+    /// Legion tracks changes per query, so it has to live across frames.
+    changed_instances: ChangedInstancesQuery,
+    /// Settings of the entities that do not override them.
+    raymarch_settings: RaymarchSettings,
+    /// The settings changed since the last upload, all instances need to be written again.
+    raymarch_settings_changed: bool,
+    slot_allocator: SlotAllocator,
+    instance_buffer: InstanceBuffer,
+    /// Slots last written in the instance buffer.
+    instance_slots: Vec<u32>,
+    asset_instances: Vec<AssetInstances>,
+    /// Raymarches with the csg interpreter, for the assets that are not compiled.
     first_stage_pipeline: wgpu::RenderPipeline,
+    /// Raymarching pipelines of the compiled assets.
+    pipeline_cache: PipelineCache,
     second_stage_pipeline: wgpu::RenderPipeline,
+    /// Counts the iterations of the raymarcher, built by the first call to `render_iterations`.
+    iterations_pipeline: std::cell::OnceCell<wgpu::RenderPipeline>,
     screen_resolution: Buffer<ScreenResolution, false>,
     albedo_tex: self::texture::Texture<AlbedoTexture>,
     normal_depth_tex: self::texture::Texture<NormalDepthTexture>,
+    depth_tex: DepthTexture,
+    cone_prepass: ConePrepass,
+    /// Only there when the device supports timestamp queries.
+    pass_timer: Option<PassTimer>,
 }
 
 impl DeferredRenderer {
-    pub(crate) fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> DeferredRenderer {
-        let first_stage_pipeline = create_first_stage_pipeline(device);
-        let second_stage_pipeline = create_second_stage_pipeline(device, config);
-        let screen_resolution = Buffer::<ScreenResolution, false>::new(&device, ScreenResolution::new(config.width, config.height));
+    pub(crate) fn new(device: &wgpu::Device, target_format: wgpu::TextureFormat, size: (u32, u32), raymarch_settings: RaymarchSettings, cone_prepass: bool) -> DeferredRenderer {
+        let interpreter_shader = create_raymarcher_shader(device, include_str!("../shaders/csg_interpreter.wgsl"));
+        let first_stage_pipeline = create_first_stage_pipeline(device, &interpreter_shader);
+        let second_stage_pipeline = create_second_stage_pipeline(device, target_format);
+        let screen_resolution = Buffer::<ScreenResolution, false>::new(device, ScreenResolution::new(size.0, size.1));
 
-        let size = (config.width, config.height);
-        
         let albedo_tex = self::texture::Texture::new(
             device, size,
             wgpu::TextureFormat::Rgba8UnormSrgb,
@@ -43,16 +93,35 @@ impl DeferredRenderer {
             device, size,
             wgpu::TextureFormat::Rgba16Float,
         );
+        let depth_tex = DepthTexture::new(device, size);
+        let cone_prepass = ConePrepass::new(device, size, &screen_resolution, cone_prepass, &interpreter_shader);
 
-        let transform_buffer = Buffer::<TransformToGpu, true>::empty(device);
+        let instance_data_buffer = Buffer::<InstanceToGpu, true>::empty(device);
+        let mut changed_instances_query = <legion::Entity>::query()
+            .filter(legion::component::<GpuSlot>() & (legion::maybe_changed::<GlobalTransform>() | legion::maybe_changed::<CsgRenderer>()));
+        let changed_instances = Box::new(move |world: &legion::World| changed_instances_query.iter(world)
+            .copied()
+            .collect());
 
         DeferredRenderer {
-            transform_buffer,
+            instance_data_buffer,
+            changed_instances,
+            raymarch_settings,
+            raymarch_settings_changed: false,
+            slot_allocator: SlotAllocator::new(),
+            instance_buffer: InstanceBuffer::new(device),
+            instance_slots: Vec::new(),
+            asset_instances: Vec::new(),
             first_stage_pipeline,
+            pipeline_cache: PipelineCache::new(),
             second_stage_pipeline,
+            iterations_pipeline: std::cell::OnceCell::new(),
             screen_resolution,
             albedo_tex,
             normal_depth_tex,
+            depth_tex,
+            cone_prepass,
+            pass_timer: PassTimer::new(device),
         }
     }
 
@@ -61,28 +130,145 @@ impl DeferredRenderer {
         // resize all temps textures
         self.albedo_tex.resize(device, new_size);
         self.normal_depth_tex.resize(device, new_size);
+        self.depth_tex.resize(device, new_size);
+        self.cone_prepass.resize(device, new_size, &self.screen_resolution);
+    }
+
+    pub(crate) fn update_uniforms(&mut self, world: &mut crate::world::World, assets: &AssetManager, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let new_entities = self.update_slots(world);
+
+        // write the instances that changed at the slot of their entity
+        let changed_entities = (self.changed_instances)(world.legion_world());
+        if self.raymarch_settings_changed {
+            // any instance may use the settings of the renderer
+            let mut query = <legion::Entity>::query().filter(legion::component::<GpuSlot>());
+            let all_entities: Vec<legion::Entity> = query.iter(world.legion_world()).copied().collect();
+            self.write_instances(world.legion_world(), all_entities, device, queue);
+            self.raymarch_settings_changed = false;
+        }
+        else {
+            self.write_instances(world.legion_world(), changed_entities, device, queue);
+        }
+        // the slot may have held another instance, make sure the new entities are written
+        self.write_instances(world.legion_world(), new_entities, device, queue);
+
+        // group the slots by asset, so each asset is a contiguous range of instances
+        let mut slots_by_asset: std::collections::BTreeMap<u64, Vec<u32>> = std::collections::BTreeMap::new();
+        let mut query = <(&CsgRenderer, &GpuSlot)>::query();
+        for (csg_renderer, slot) in query.iter(world.legion_world()) {
+            slots_by_asset.entry(csg_renderer.asset_id())
+                .or_default()
+                .push(slot.index());
+        }
+
+        let mut instance_slots = Vec::new();
+        self.asset_instances.clear();
+        for (asset_id, slots) in slots_by_asset.into_iter() {
+            let start = instance_slots.len() as u32;
+            instance_slots.extend(slots);
+            let end = instance_slots.len() as u32;
+            self.asset_instances.push(AssetInstances { asset_id, instances: start..end });
+        }
+
+        // compiled assets need their pipelines before they are drawn
+        for asset_instances in self.asset_instances.iter() {
+            if let Some(compiled) = assets.get::<CsgObjectAsset>(asset_instances.asset_id).and_then(|csg| csg.compiled()) {
+                self.pipeline_cache.prepare(device, compiled);
+            }
+        }
+
+        // if the instances did not change, the buffer is up to date
+        if instance_slots != self.instance_slots {
+            self.instance_buffer.update(device, queue, &instance_slots);
+            self.instance_slots = instance_slots;
+        }
     }
 
-    pub(crate) fn update_uniforms(&mut self, world: &mut crate::world::World, queue: &wgpu::Queue) {
-        let mut query = <(&mut Transform, &CsgRenderer)>::query();
-        for (i, (transform, _csg_renderer)) in query.iter_mut(world.legion_world_mut()).enumerate() {
-            if transform.is_dirty() {
-                self.transform_buffer.update_elem(queue, i as u64, transform.recompute_matrix());
-                transform.set_clean();
+    /// Drop the compiled pipelines no loaded asset uses anymore. To be called once the assets are reloaded.
+    pub(crate) fn evict_unused_pipelines(&mut self, assets: &AssetManager) {
+        self.pipeline_cache.evict_unused(assets.values::<CsgObjectAsset>().filter_map(|csg| csg.compiled()));
+    }
+
+    pub(crate) fn raymarch_settings(&self) -> RaymarchSettings {
+        self.raymarch_settings
+    }
+
+    pub(crate) fn set_raymarch_settings(&mut self, raymarch_settings: RaymarchSettings) {
+        if raymarch_settings != self.raymarch_settings {
+            self.raymarch_settings = raymarch_settings;
+            self.raymarch_settings_changed = true;
+        }
+    }
+
+    pub(crate) fn cone_prepass(&self) -> bool {
+        self.cone_prepass.enabled()
+    }
+
+    pub(crate) fn set_cone_prepass(&mut self, enabled: bool) {
+        self.cone_prepass.set_enabled(enabled);
+    }
+
+    /// Write the transform and settings of the entities at their slot.
+    fn write_instances(&mut self, world: &legion::World, entities: Vec<legion::Entity>, device: &wgpu::Device, queue: &wgpu::Queue) {
+        for entity in entities.into_iter() {
+            let Ok(entry) = world.entry_ref(entity) else {
+                continue;
+            };
+            let (Ok(transform), Ok(csg_renderer), Ok(slot)) = (
+                entry.get_component::<GlobalTransform>(),
+                entry.get_component::<CsgRenderer>(),
+                entry.get_component::<GpuSlot>(),
+            ) else {
+                continue;
+            };
+            let settings = csg_renderer.raymarch_settings().unwrap_or(self.raymarch_settings);
+            let instance = InstanceToGpu::new(transform.to_gpu(), settings.to_gpu());
+            self.instance_data_buffer.update_elem(device, queue, slot.index() as u64, instance);
+        }
+    }
+
+    /// Free the slots of the entities that are not rendered anymore, and give one to the new ones.
+    /// Returns the entities that got a slot.
+    fn update_slots(&mut self, world: &mut crate::world::World) -> Vec<legion::Entity> {
+        let legion_world = world.legion_world_mut();
+
+        let stale_entities: Vec<legion::Entity> = self.slot_allocator.entities()
+            .filter(|entity| match legion_world.entry_ref(**entity) {
+                Ok(entry) => entry.get_component::<CsgRenderer>().is_err(),
+                // despawned
+                Err(_) => true,
+            })
+            .copied()
+            .collect();
+        for entity in stale_entities.into_iter() {
+            self.slot_allocator.free(entity);
+            if let Some(mut entry) = legion_world.entry(entity) {
+                entry.remove_component::<GpuSlot>();
+            }
+        }
+
+        let mut query = <(legion::Entity, &CsgRenderer)>::query()
+            .filter(legion::component::<GlobalTransform>() & !legion::component::<GpuSlot>());
+        let new_entities: Vec<legion::Entity> = query.iter(legion_world)
+            .map(|(entity, _)| *entity)
+            .collect();
+        for entity in new_entities.iter() {
+            let slot = self.slot_allocator.allocate(*entity);
+            if let Some(mut entry) = legion_world.entry(*entity) {
+                entry.add_component(slot);
             }
         }
+        new_entities
     }
 
-    pub(crate) fn render(&self, world: &crate::world::World, assets: &AssetManager, device: &wgpu::Device, queue: &wgpu::Queue, surface: &wgpu::Surface) -> Result<(), wgpu::SurfaceError> {
-        let output = surface.get_current_texture()?;
+    /// Record both stages of the deferred renderer in the encoder, writing the lit frame into the output view.
+    /// The encoder is not submitted, this is up to the caller.
+    pub(crate) fn render(&self, world: &crate::world::World, assets: &AssetManager, encoder: &mut wgpu::CommandEncoder, output_view: &wgpu::TextureView) {
+        self.render_cone_prepass(world, assets, encoder, self.timestamp_writes(TimedPass::ConePrepass));
 
-        let output_view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
         let albedo_view = self.albedo_tex.get_view();
         let normal_depth_view = self.normal_depth_tex.get_view();
-
-        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("deferred renderer encoder"),
-        });
+        let depth_view = self.depth_tex.get_view();
         
         let color_attachments = [
             Some(wgpu::RenderPassColorAttachment {
@@ -110,31 +296,33 @@ impl DeferredRenderer {
         let mut first_stage_render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("first stage render pass"),
             color_attachments: &color_attachments,
-            depth_stencil_attachment: None,
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
             occlusion_query_set: None,
-            timestamp_writes: None,
+            timestamp_writes: self.timestamp_writes(TimedPass::FirstStage),
         });
 
-        first_stage_render_pass.set_pipeline(&self.first_stage_pipeline);
-        first_stage_render_pass.set_bind_group(0, &world.main_camera().bind_group(), &[]);
-        first_stage_render_pass.set_bind_group(1, &self.screen_resolution.bind_group(), &[]);
-
-        let mut query = <(&Transform, &CsgRenderer)>::query();
-        for (i, (_transform, csg_renderer)) in query.iter(world.legion_world()).enumerate() {
-            
-            // todo: instance rendering by asset
-            let csg = match assets.get::<CsgObjectAsset>(csg_renderer.asset_id()) {
-                Some(csg) => csg,
-                None => {
-                    println!("Asset not loaded: unable to render !");
-                    continue;
-                }
+        first_stage_render_pass.set_bind_group(0, world.main_camera().bind_group(), &[]);
+        first_stage_render_pass.set_bind_group(1, self.cone_prepass.raymarch_bind_group(), &[]);
+        first_stage_render_pass.set_bind_group(3, self.instance_data_buffer.bind_group(), &[]);
+        first_stage_render_pass.set_vertex_buffer(0, self.instance_buffer.slice());
+
+        for asset_instances in self.asset_instances.iter() {
+            // entities can reference an asset before it is loaded, they show up once it is
+            let Some(csg) = assets.get::<CsgObjectAsset>(asset_instances.asset_id) else {
+                continue;
             };
-            
-            first_stage_render_pass.set_bind_group(2, &csg.bind_group(), &[]);
-            first_stage_render_pass.set_bind_group(3, self.transform_buffer.bind_group(), &[i as u32]);
-            // draw the hard coded bounding box
-            first_stage_render_pass.draw(0..36, 0..1);
+
+            first_stage_render_pass.set_pipeline(self.first_stage_pipeline_for(csg));
+            first_stage_render_pass.set_bind_group(2, csg.bind_group(), &[]);
+            // draw the hard coded bounding box, once for every entity using this asset
+            first_stage_render_pass.draw(0..36, asset_instances.instances.clone());
         }
 
         drop(first_stage_render_pass);
@@ -142,7 +330,7 @@ impl DeferredRenderer {
         let mut second_stage_render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("second stage render pass"),
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &output_view,
+                view: output_view,
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Clear(wgpu::Color {
@@ -153,12 +341,12 @@ impl DeferredRenderer {
             })],
             depth_stencil_attachment: None,
             occlusion_query_set: None,
-            timestamp_writes: None,
+            timestamp_writes: self.timestamp_writes(TimedPass::SecondStage),
         });
 
         // second stage
         second_stage_render_pass.set_pipeline(&self.second_stage_pipeline);
-        second_stage_render_pass.set_bind_group(0, &self.screen_resolution.bind_group(), &[]);
+        second_stage_render_pass.set_bind_group(0, self.screen_resolution.bind_group(), &[]);
         second_stage_render_pass.set_bind_group(1, self.albedo_tex.bind_group(), &[]);
         second_stage_render_pass.set_bind_group(2, self.normal_depth_tex.bind_group(), &[]);
         // draw the hard coded quad
@@ -166,24 +354,149 @@ impl DeferredRenderer {
         
         drop(second_stage_render_pass);
 
-        // submit will accept anything that implements IntoIter
-        queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+        if let Some(pass_timer) = &self.pass_timer {
+            pass_timer.resolve(encoder);
+        }
+    }
+
+    /// Gpu time of each pass of the last submitted frame, if the device supports timestamp queries.
+    pub(crate) fn read_pass_timings(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Option<PassTimings>, crate::error::MorpheusError> {
+        self.pass_timer.as_ref()
+            .map(|pass_timer| pass_timer.read(device, queue))
+            .transpose()
+    }
+
+    fn timestamp_writes(&self, pass: TimedPass) -> Option<wgpu::RenderPassTimestampWrites<'_>> {
+        self.pass_timer.as_ref().map(|pass_timer| pass_timer.timestamp_writes(pass))
+    }
+
+    /// Record the cone prepass, then the number of iterations the raymarcher takes for each pixel into the view,
+    /// instead of the frame. The view should have the [`ITERATIONS_FORMAT`].
+    /// Every asset is marched with the interpreter here, the compiled ones take the same steps.
+    pub(crate) fn render_iterations(&self, device: &wgpu::Device, world: &crate::world::World, assets: &AssetManager, encoder: &mut wgpu::CommandEncoder, output_view: &wgpu::TextureView) {
+        // not timed, the timings are the ones of the frames
+        self.render_cone_prepass(world, assets, encoder, None);
+
+        // only used to measure the raymarcher, so it is only built the first time
+        let iterations_pipeline = self.iterations_pipeline.get_or_init(|| {
+            let interpreter_shader = create_raymarcher_shader(device, include_str!("../shaders/csg_interpreter.wgsl"));
+            create_iterations_pipeline(device, &interpreter_shader)
+        });
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("iterations render pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.0, g: 0.0, b: 0.0, a: 0.0,
+                    }),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+
+        render_pass.set_pipeline(iterations_pipeline);
+        render_pass.set_bind_group(0, world.main_camera().bind_group(), &[]);
+        render_pass.set_bind_group(1, self.cone_prepass.raymarch_bind_group(), &[]);
+        render_pass.set_bind_group(3, self.instance_data_buffer.bind_group(), &[]);
+        render_pass.set_vertex_buffer(0, self.instance_buffer.slice());
+
+        for asset_instances in self.asset_instances.iter() {
+            let Some(csg) = assets.get::<CsgObjectAsset>(asset_instances.asset_id) else {
+                continue;
+            };
+            render_pass.set_bind_group(2, csg.bind_group(), &[]);
+            render_pass.draw(0..36, asset_instances.instances.clone());
+        }
+    }
+
+    /// The pipeline compiled for the asset if there is one, the interpreter otherwise.
+    fn first_stage_pipeline_for(&self, csg: &CsgObjectAsset) -> &wgpu::RenderPipeline {
+        csg.compiled()
+            .and_then(|compiled| self.pipeline_cache.get(compiled))
+            .map(|pipelines| &pipelines.first_stage)
+            .unwrap_or(&self.first_stage_pipeline)
+    }
 
-        Ok(())
+    fn cone_prepass_pipeline_for(&self, csg: &CsgObjectAsset) -> &wgpu::RenderPipeline {
+        csg.compiled()
+            .and_then(|compiled| self.pipeline_cache.get(compiled))
+            .map(|pipelines| &pipelines.cone_prepass)
+            .unwrap_or(self.cone_prepass.pipeline())
+    }
+
+    /// Record the cone marching prepass in the encoder.
+    /// When disabled, the distances are only cleared so the raymarcher starts its rays at the proxy boxes.
+    fn render_cone_prepass(&self, world: &crate::world::World, assets: &AssetManager, encoder: &mut wgpu::CommandEncoder, timestamp_writes: Option<wgpu::RenderPassTimestampWrites>) {
+        let distance_view = self.cone_prepass.distance_view();
+        let depth_view = self.cone_prepass.depth_view();
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("cone prepass render pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &distance_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                        r: 0.0, g: 0.0, b: 0.0, a: 0.0,
+                    }),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes,
+        });
+
+        if !self.cone_prepass.enabled() {
+            return;
+        }
+
+        render_pass.set_bind_group(0, world.main_camera().bind_group(), &[]);
+        render_pass.set_bind_group(1, self.screen_resolution.bind_group(), &[]);
+        render_pass.set_bind_group(3, self.instance_data_buffer.bind_group(), &[]);
+        render_pass.set_vertex_buffer(0, self.instance_buffer.slice());
+
+        for asset_instances in self.asset_instances.iter() {
+            let Some(csg) = assets.get::<CsgObjectAsset>(asset_instances.asset_id) else {
+                continue;
+            };
+            render_pass.set_pipeline(self.cone_prepass_pipeline_for(csg));
+            render_pass.set_bind_group(2, csg.bind_group(), &[]);
+            render_pass.draw(0..36, asset_instances.instances.clone());
+        }
     }
 }
 
-fn create_first_stage_pipeline(device: &wgpu::Device) -> wgpu::RenderPipeline {
-    let shader = device.create_shader_module(wgpu::include_wgsl!("../shaders/raymarcher.wgsl"));
-        
+/// The raymarcher shader, completed with the given definition of `scene_sdf`.
+fn create_raymarcher_shader(device: &wgpu::Device, scene_sdf: &str) -> wgpu::ShaderModule {
+    let source = format!("{}\n{}", include_str!("../shaders/raymarcher.wgsl"), scene_sdf);
+    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("raymarcher shader"),
+        source: wgpu::ShaderSource::Wgsl(source.into()),
+    })
+}
+
+fn create_first_stage_pipeline(device: &wgpu::Device, shader: &wgpu::ShaderModule) -> wgpu::RenderPipeline {
     let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
         label: Some("first stage pipeline layout"),
         bind_group_layouts: &[
             &Buffer::<CameraToGpu, false>::bind_group_layout(device),
-            &Buffer::<ScreenResolution, false>::bind_group_layout(device),
+            &ConePrepass::raymarch_bind_group_layout(device),
             &CsgBuffer::bind_group_layout(device),
-            &Buffer::<TransformToGpu, false>::bind_group_layout(device),
+            &Buffer::<InstanceToGpu, false>::bind_group_layout(device),
         ],
         push_constant_ranges: &[],
     });
@@ -208,12 +521,12 @@ fn create_first_stage_pipeline(device: &wgpu::Device) -> wgpu::RenderPipeline {
         label: Some("first stage render pipeline"),
         layout: Some(&render_pipeline_layout),
         vertex: wgpu::VertexState {
-            module: &shader,
+            module: shader,
             entry_point: "vs_main",
-            buffers: &[],
+            buffers: &[InstanceBuffer::layout()],
         },
         fragment: Some(wgpu::FragmentState {
-            module: &shader,
+            module: shader,
             entry_point: "fs_main",
             targets: &fragment_target,
         }),
@@ -221,7 +534,10 @@ fn create_first_stage_pipeline(device: &wgpu::Device) -> wgpu::RenderPipeline {
             topology: wgpu::PrimitiveTopology::TriangleList,
             strip_index_format: None,
             front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
+            // draw the back faces of the proxy cube: they are visible from outside as well as from inside,
+            // so objects keep rendering when the camera enters their bounding box.
+            // Rays always start at the box entry point (or the camera if inside), so this does not change the result.
+            cull_mode: Some(wgpu::Face::Front),
             // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
             polygon_mode: wgpu::PolygonMode::Fill,
             // Requires Features::DEPTH_CLIP_CONTROL
@@ -229,7 +545,14 @@ fn create_first_stage_pipeline(device: &wgpu::Device) -> wgpu::RenderPipeline {
             // Requires Features::CONSERVATIVE_RASTERIZATION
             conservative: false,
         },
-        depth_stencil: None,
+        // the raymarcher writes the hit depth, so objects are sorted by their actual surface
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: self::depth_texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
         multisample: wgpu::MultisampleState {
             count: 1,
             mask: !0,
@@ -240,7 +563,64 @@ fn create_first_stage_pipeline(device: &wgpu::Device) -> wgpu::RenderPipeline {
 }
 
 
-fn create_second_stage_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::RenderPipeline {
+/// Pipeline of `fs_iterations`: the raymarcher, summing its iteration counts instead of writing the g-buffer.
+/// There is no depth test, so the objects hidden behind others are counted too.
+fn create_iterations_pipeline(device: &wgpu::Device, shader: &wgpu::ShaderModule) -> wgpu::RenderPipeline {
+    let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("iterations pipeline layout"),
+        bind_group_layouts: &[
+            &Buffer::<CameraToGpu, false>::bind_group_layout(device),
+            &ConePrepass::raymarch_bind_group_layout(device),
+            &CsgBuffer::bind_group_layout(device),
+            &Buffer::<InstanceToGpu, false>::bind_group_layout(device),
+        ],
+        push_constant_ranges: &[],
+    });
+
+    let add = wgpu::BlendComponent {
+        src_factor: wgpu::BlendFactor::One,
+        dst_factor: wgpu::BlendFactor::One,
+        operation: wgpu::BlendOperation::Add,
+    };
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("iterations render pipeline"),
+        layout: Some(&render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[InstanceBuffer::layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_iterations",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: ITERATIONS_FORMAT,
+                blend: Some(wgpu::BlendState { color: add, alpha: add }),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        // same rasterization as the first stage, so the same pixels are marched
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Front),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+fn create_second_stage_pipeline(device: &wgpu::Device, target_format: wgpu::TextureFormat) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(wgpu::include_wgsl!("../shaders/deferred_lighting.wgsl"));
         
     let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
@@ -265,7 +645,7 @@ fn create_second_stage_pipeline(device: &wgpu::Device, config: &wgpu::SurfaceCon
             module: &shader,
             entry_point: "fs_main",
             targets: &[Some(wgpu::ColorTargetState {
-                format: config.format,
+                format: target_format,
                 blend: None,
                 write_mask: wgpu::ColorWrites::ALL,
             })],
@@ -0,0 +1,200 @@
+use super::depth_texture::{DepthTexture, DEPTH_FORMAT};
+use super::instance_buffer::InstanceBuffer;
+use super::instance_data::InstanceToGpu;
+use crate::renderer::assets::csg::csg_buffer::CsgBuffer;
+use crate::renderer::buffer::{Buffer, BufferElem};
+use crate::renderer::has_bind_group_layout::HasBindGroupLayout;
+use crate::renderer::screen_resolution::ScreenResolution;
+use crate::world::camera::CameraToGpu;
+
+
+/// Side in pixels of the screen tiles marched by the prepass, must match `CONE_TILE_SIZE` in the raymarcher.
+pub(super) const CONE_TILE_SIZE: u32 = 8;
+
+/// Format of the distances found by the prepass.
+const DISTANCE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Float;
+
+/// Low resolution pass that marches a cone per tile of the screen, containing the rays of all its pixels.
+/// The distance up to which the cone is empty is written for each tile,
+/// so the full resolution raymarcher can start its rays from there.
+pub(super) struct ConePrepass {
+    enabled: bool,
+    pipeline: wgpu::RenderPipeline,
+    /// Distance along the rays that is empty, per tile.
+    distance_texture: wgpu::Texture,
+    /// Keeps the closest distance when several objects cover a tile.
+    depth_texture: DepthTexture,
+    /// Screen resolution and distances, as read by the full resolution raymarcher.
+    raymarch_bind_group: wgpu::BindGroup,
+}
+
+impl ConePrepass {
+    /// The pipeline uses the raymarcher shader, that interprets the csg.
+    /// Compiled csg get their own pipelines, see [`create_pipeline`].
+    pub(super) fn new(device: &wgpu::Device, size: (u32, u32), screen_resolution: &Buffer<ScreenResolution, false>, enabled: bool, shader: &wgpu::ShaderModule) -> ConePrepass {
+        let distance_texture = create_distance_texture(device, tiled_size(size));
+        let raymarch_bind_group = create_raymarch_bind_group(device, screen_resolution, &distance_texture);
+
+        ConePrepass {
+            enabled,
+            pipeline: create_pipeline(device, shader),
+            distance_texture,
+            depth_texture: DepthTexture::new(device, tiled_size(size)),
+            raymarch_bind_group,
+        }
+    }
+
+    pub(super) fn resize(&mut self, device: &wgpu::Device, new_size: (u32, u32), screen_resolution: &Buffer<ScreenResolution, false>) {
+        self.distance_texture = create_distance_texture(device, tiled_size(new_size));
+        self.depth_texture.resize(device, tiled_size(new_size));
+        self.raymarch_bind_group = create_raymarch_bind_group(device, screen_resolution, &self.distance_texture);
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub(super) fn pipeline(&self) -> &wgpu::RenderPipeline {
+        &self.pipeline
+    }
+
+    pub(super) fn distance_view(&self) -> wgpu::TextureView {
+        self.distance_texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    pub(super) fn depth_view(&self) -> wgpu::TextureView {
+        self.depth_texture.get_view()
+    }
+
+    pub(super) fn raymarch_bind_group(&self) -> &wgpu::BindGroup {
+        &self.raymarch_bind_group
+    }
+
+    /// Layout of the screen resolution and tile distances read by the full resolution raymarcher.
+    pub(super) fn raymarch_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: ScreenResolution::BINDING,
+                    visibility: ScreenResolution::VISIBILITY,
+                    ty: ScreenResolution::BINDING_TYPE,
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("cone prepass raymarch bind group layout"),
+        })
+    }
+}
+
+/// Size of the prepass textures, a texel per tile. Partial tiles on the borders get a texel too.
+fn tiled_size(size: (u32, u32)) -> (u32, u32) {
+    (
+        size.0.div_ceil(CONE_TILE_SIZE).max(1),
+        size.1.div_ceil(CONE_TILE_SIZE).max(1),
+    )
+}
+
+fn create_distance_texture(device: &wgpu::Device, size: (u32, u32)) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("cone prepass distance texture"),
+        size: wgpu::Extent3d {
+            width: size.0, height: size.1, depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: DISTANCE_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[DISTANCE_FORMAT],
+    })
+}
+
+fn create_raymarch_bind_group(device: &wgpu::Device, screen_resolution: &Buffer<ScreenResolution, false>, distance_texture: &wgpu::Texture) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &ConePrepass::raymarch_bind_group_layout(device),
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: ScreenResolution::BINDING,
+                resource: screen_resolution.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(
+                    &distance_texture.create_view(&wgpu::TextureViewDescriptor::default())
+                ),
+            },
+        ],
+        label: Some("cone prepass raymarch bind group"),
+    })
+}
+
+/// The shader is the one of the full resolution raymarcher, so both march the exact same sdf.
+pub(super) fn create_pipeline(device: &wgpu::Device, shader: &wgpu::ShaderModule) -> wgpu::RenderPipeline {
+    let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("cone prepass pipeline layout"),
+        bind_group_layouts: &[
+            &Buffer::<CameraToGpu, false>::bind_group_layout(device),
+            &Buffer::<ScreenResolution, false>::bind_group_layout(device),
+            &CsgBuffer::bind_group_layout(device),
+            &Buffer::<InstanceToGpu, false>::bind_group_layout(device),
+        ],
+        push_constant_ranges: &[],
+    });
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("cone prepass render pipeline"),
+        layout: Some(&render_pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_cone_prepass",
+            buffers: &[InstanceBuffer::layout()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_cone_prepass",
+            targets: &[Some(wgpu::ColorTargetState {
+                format: DISTANCE_FORMAT,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            // back faces, like the full resolution pass, so the camera can be inside the boxes
+            cull_mode: Some(wgpu::Face::Front),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        // the depth written is increasing with the distance, so the closest one stays in the distance texture
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
@@ -0,0 +1,41 @@
+
+
+/// Format of the scene depth buffer.
+pub(super) const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// Depth attachment of the first stage, so that objects occlude each other.
+/// The raymarcher writes the depth of the actual hit, not the one of the proxy geometry.
+pub(super) struct DepthTexture {
+    texture: wgpu::Texture,
+}
+
+impl DepthTexture {
+    pub(super) fn new(device: &wgpu::Device, size: (u32, u32)) -> DepthTexture {
+        DepthTexture {
+            texture: create_depth_texture(device, size),
+        }
+    }
+
+    pub(super) fn get_view(&self) -> wgpu::TextureView {
+        self.texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    pub(super) fn resize(&mut self, device: &wgpu::Device, new_size: (u32, u32)) {
+        self.texture = create_depth_texture(device, new_size);
+    }
+}
+
+fn create_depth_texture(device: &wgpu::Device, size: (u32, u32)) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("depth texture"),
+        size: wgpu::Extent3d {
+            width: size.0, height: size.1, depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: DEPTH_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[DEPTH_FORMAT],
+    })
+}
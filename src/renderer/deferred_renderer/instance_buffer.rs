@@ -0,0 +1,56 @@
+
+
+/// Vertex buffer of the instances to draw, one gpu slot (u32) per instance.
+/// Instances of the same asset are contiguous, so each asset is drawn with a single instanced draw call.
+pub(super) struct InstanceBuffer {
+    capacity: u64,
+    buffer: wgpu::Buffer,
+}
+
+/// Number of bytes of an instance in the buffer.
+const INSTANCE_SIZE: u64 = std::mem::size_of::<u32>() as u64;
+
+impl InstanceBuffer {
+    pub(super) fn new(device: &wgpu::Device) -> InstanceBuffer {
+        let capacity = 16; // grows when needed
+        InstanceBuffer {
+            capacity,
+            buffer: create_instance_buffer(device, capacity),
+        }
+    }
+
+    /// Overwrite the instances with the given slots.
+    pub(super) fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, slots: &[u32]) {
+        let count = slots.len() as u64;
+        if count > self.capacity {
+            // everything is rewritten, no need to copy the old content
+            self.capacity = count.max(2 * self.capacity);
+            self.buffer = create_instance_buffer(device, self.capacity);
+        }
+        if !slots.is_empty() {
+            queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(slots));
+        }
+    }
+
+    pub(super) fn slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..)
+    }
+
+    pub(super) fn layout() -> wgpu::VertexBufferLayout<'static> {
+        const ATTRIBUTES: [wgpu::VertexAttribute; 1] = wgpu::vertex_attr_array![0 => Uint32];
+        wgpu::VertexBufferLayout {
+            array_stride: INSTANCE_SIZE,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+fn create_instance_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("instance buffer"),
+        size: capacity * INSTANCE_SIZE,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
@@ -0,0 +1,42 @@
+use crate::renderer::buffer::BufferElem;
+use crate::renderer::raymarch_settings::RaymarchSettingsToGpu;
+use crate::world::components::transform::TransformToGpu;
+
+
+/// Everything the raymarcher needs to know about an instance, stored at the gpu slot of its entity.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub(super) struct InstanceToGpu {
+    transform: TransformToGpu,
+    settings: RaymarchSettingsToGpu,
+}
+
+unsafe impl bytemuck::Zeroable for InstanceToGpu {}
+unsafe impl bytemuck::Pod for InstanceToGpu {}
+
+const INSTANCE_DATA_SIZE: usize = std::mem::size_of::<InstanceToGpu>();
+
+impl BufferElem for InstanceToGpu {
+    const BINDING: u32 = 0;
+    const BINDING_TYPE: wgpu::BindingType = wgpu::BindingType::Buffer {
+        ty: wgpu::BufferBindingType::Storage { read_only: true },
+        has_dynamic_offset: false,
+        min_binding_size: None,
+    };
+    #[cfg(debug_assertions)]
+    const LABEL: &'static str = "instance data";
+    const VISIBILITY: wgpu::ShaderStages = wgpu::ShaderStages::VERTEX_FRAGMENT;
+    const SIZE: u64 = INSTANCE_DATA_SIZE as u64;
+    fn to_bytes(&self) -> &[u8] {
+        bytemuck::cast_ref::<InstanceToGpu, [u8; INSTANCE_DATA_SIZE]>(self)
+    }
+}
+
+impl InstanceToGpu {
+    pub(super) fn new(transform: TransformToGpu, settings: RaymarchSettingsToGpu) -> InstanceToGpu {
+        InstanceToGpu {
+            transform,
+            settings,
+        }
+    }
+}
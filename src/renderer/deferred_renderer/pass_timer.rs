@@ -0,0 +1,113 @@
+use crate::error::MorpheusError;
+
+
+/// Passes of a frame, in the order they run. Each one gets a begin and an end timestamp.
+const PASS_COUNT: u32 = 3;
+const TIMESTAMP_COUNT: u32 = PASS_COUNT * 2;
+const TIMESTAMPS_SIZE: u64 = TIMESTAMP_COUNT as u64 * std::mem::size_of::<u64>() as u64;
+
+/// Gpu time spent in each pass of a frame, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PassTimings {
+    /// Cone marching prepass. When it is disabled, this only clears the tile distances.
+    pub cone_prepass: f64,
+    /// Raymarching of the objects into the g-buffer.
+    pub first_stage: f64,
+    /// Lighting of the g-buffer into the output view.
+    pub second_stage: f64,
+}
+
+/// Index of the passes in the timestamp query set.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum TimedPass {
+    ConePrepass = 0,
+    FirstStage = 1,
+    SecondStage = 2,
+}
+
+/// Timestamps written by the passes of the deferred renderer, when the device supports timestamp queries.
+/// They are resolved at the end of each frame, and only read back on demand.
+pub(super) struct PassTimer {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    read_back_buffer: wgpu::Buffer,
+}
+
+impl PassTimer {
+    /// None if the device was created without [`wgpu::Features::TIMESTAMP_QUERY`].
+    pub(super) fn new(device: &wgpu::Device) -> Option<PassTimer> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("pass timer query set"),
+            ty: wgpu::QueryType::Timestamp,
+            count: TIMESTAMP_COUNT,
+        });
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("pass timer resolve buffer"),
+            size: TIMESTAMPS_SIZE,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let read_back_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("pass timer read back buffer"),
+            size: TIMESTAMPS_SIZE,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        Some(PassTimer {
+            query_set,
+            resolve_buffer,
+            read_back_buffer,
+        })
+    }
+
+    /// Timestamps to write at the beginning and the end of the pass.
+    pub(super) fn timestamp_writes(&self, pass: TimedPass) -> wgpu::RenderPassTimestampWrites<'_> {
+        let begin = pass as u32 * 2;
+        wgpu::RenderPassTimestampWrites {
+            query_set: &self.query_set,
+            beginning_of_pass_write_index: Some(begin),
+            end_of_pass_write_index: Some(begin + 1),
+        }
+    }
+
+    /// Copy the timestamps of the frame where they can be read back, after all the timed passes.
+    pub(super) fn resolve(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.resolve_query_set(&self.query_set, 0..TIMESTAMP_COUNT, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(&self.resolve_buffer, 0, &self.read_back_buffer, 0, TIMESTAMPS_SIZE);
+    }
+
+    /// Timings of the last submitted frame.
+    /// This blocks until the gpu is done with all the submitted work.
+    pub(super) fn read(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<PassTimings, MorpheusError> {
+        let buffer_slice = self.read_back_buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            // the receiver is waiting on us, can't fail
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv().unwrap_or(Err(wgpu::BufferAsyncError))?;
+
+        let mapped = buffer_slice.get_mapped_range();
+        let timestamps: Vec<u64> = bytemuck::cast_slice(&mapped).to_vec();
+        drop(mapped);
+        self.read_back_buffer.unmap();
+
+        // ticks to milliseconds
+        let period = queue.get_timestamp_period() as f64 / 1_000_000.0;
+        let duration = |pass: TimedPass| {
+            let begin = pass as usize * 2;
+            timestamps[begin + 1].saturating_sub(timestamps[begin]) as f64 * period
+        };
+        Ok(PassTimings {
+            cone_prepass: duration(TimedPass::ConePrepass),
+            first_stage: duration(TimedPass::FirstStage),
+            second_stage: duration(TimedPass::SecondStage),
+        })
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::renderer::assets::csg::compiled_sdf::CompiledSdf;
+
+
+/// Raymarching pipelines built around the scene_sdf of a compiled csg.
+pub(super) struct CsgPipelines {
+    pub(super) first_stage: wgpu::RenderPipeline,
+    pub(super) cone_prepass: wgpu::RenderPipeline,
+}
+
+/// Pipelines of the compiled assets, by the encoded node ids of their tree structure.
+/// They are kept as long as a loaded asset has this structure: building them is what we want to avoid,
+/// but assets replaced by trees of other structures would otherwise pile up pipelines.
+pub(super) struct PipelineCache {
+    pipelines: HashMap<Vec<u32>, CsgPipelines>,
+}
+
+impl PipelineCache {
+    pub(super) fn new() -> PipelineCache {
+        PipelineCache {
+            pipelines: HashMap::new(),
+        }
+    }
+
+    /// Build the pipelines of the compiled csg, unless a tree with the same structure already did.
+    pub(super) fn prepare(&mut self, device: &wgpu::Device, compiled: &CompiledSdf) {
+        // called every frame, only copy the ids for a new structure
+        if self.pipelines.contains_key(compiled.node_ids()) {
+            return;
+        }
+        let shader = super::create_raymarcher_shader(device, compiled.source());
+        self.pipelines.insert(compiled.node_ids().to_vec(), CsgPipelines {
+            first_stage: super::create_first_stage_pipeline(device, &shader),
+            cone_prepass: super::cone_prepass::create_pipeline(device, &shader),
+        });
+    }
+
+    pub(super) fn get(&self, compiled: &CompiledSdf) -> Option<&CsgPipelines> {
+        self.pipelines.get(compiled.node_ids())
+    }
+
+    /// Drop the pipelines of the structures that none of the given compiled csg have.
+    pub(super) fn evict_unused<'a>(&mut self, used: impl Iterator<Item = &'a CompiledSdf>) {
+        let used: HashSet<&[u32]> = used.map(CompiledSdf::node_ids).collect();
+        self.pipelines.retain(|node_ids, _| used.contains(node_ids.as_slice()));
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+use crate::world::components::gpu_slot::GpuSlot;
+
+
+/// Gives every rendered entity a stable slot in the gpu transform buffer.
+/// Freed slots are reused first, so the buffer stays as packed as possible.
+pub(super) struct SlotAllocator {
+    slots: HashMap<legion::Entity, GpuSlot>,
+    free_slots: Vec<GpuSlot>,
+    next_slot: u32,
+}
+
+impl SlotAllocator {
+    pub(super) fn new() -> SlotAllocator {
+        SlotAllocator {
+            slots: HashMap::new(),
+            free_slots: Vec::new(),
+            next_slot: 0,
+        }
+    }
+
+    /// Get a slot for the entity. If it already had one, this is the same.
+    pub(super) fn allocate(&mut self, entity: legion::Entity) -> GpuSlot {
+        if let Some(slot) = self.slots.get(&entity) {
+            return *slot;
+        }
+        let slot = match self.free_slots.pop() {
+            Some(slot) => slot,
+            None => {
+                let slot = GpuSlot::new(self.next_slot);
+                self.next_slot += 1;
+                slot
+            }
+        };
+        self.slots.insert(entity, slot);
+        slot
+    }
+
+    /// Release the slot of the entity, so it can be given to another one.
+    pub(super) fn free(&mut self, entity: legion::Entity) -> Option<GpuSlot> {
+        let slot = self.slots.remove(&entity)?;
+        self.free_slots.push(slot);
+        Some(slot)
+    }
+
+    /// Entities that currently own a slot.
+    pub(super) fn entities(&self) -> impl Iterator<Item = &legion::Entity> {
+        self.slots.keys()
+    }
+}
@@ -0,0 +1,118 @@
+use crate::error::MorpheusError;
+
+
+/// Format of the offscreen color texture.
+/// Pixels read back from the target are 4 bytes, rgba, in this format.
+pub(crate) const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Owned color texture that the renderer can draw into when there is no window surface.
+/// The content of the last rendered frame can be copied back to the cpu.
+pub(crate) struct OffscreenTarget {
+    texture: wgpu::Texture,
+    format: wgpu::TextureFormat,
+    size: (u32, u32),
+}
+
+impl OffscreenTarget {
+    pub(crate) fn new(device: &wgpu::Device, size: (u32, u32)) -> OffscreenTarget {
+        OffscreenTarget::with_format(device, size, OFFSCREEN_FORMAT)
+    }
+
+    /// Target of another format, that must have a single aspect.
+    pub(crate) fn with_format(device: &wgpu::Device, size: (u32, u32), format: wgpu::TextureFormat) -> OffscreenTarget {
+        OffscreenTarget {
+            texture: create_offscreen_texture(device, size, format),
+            format,
+            size,
+        }
+    }
+
+    pub(crate) fn resize(&mut self, device: &wgpu::Device, new_size: (u32, u32)) {
+        self.texture = create_offscreen_texture(device, new_size, self.format);
+        self.size = new_size;
+    }
+
+    pub(crate) fn view(&self) -> wgpu::TextureView {
+        self.texture.create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    /// Copy the texture content into a tightly packed buffer, row by row from the top left.
+    /// This blocks until the gpu is done with all the submitted work.
+    pub(crate) fn read_pixels(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Vec<u8>, MorpheusError> {
+        let (width, height) = self.size;
+        // texture to buffer copies requires rows to be aligned,
+        // so we copy into a padded buffer and remove the padding afterwards.
+        let pixel_size = self.format.block_size(None).expect("Offscreen targets have a single aspect");
+        let unpadded_bytes_per_row = width * pixel_size;
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(alignment) * alignment;
+
+        let read_back_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("offscreen read back buffer"),
+            size: padded_bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("offscreen read back encoder"),
+        });
+
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &read_back_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            wgpu::Extent3d {
+                width, height, depth_or_array_layers: 1,
+            },
+        );
+
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let buffer_slice = read_back_buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            // the receiver is waiting on us, can't fail
+            let _ = sender.send(result);
+        });
+        // wait for the copy and the mapping to be done
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv().unwrap_or(Err(wgpu::BufferAsyncError))?;
+
+        let padded_pixels = buffer_slice.get_mapped_range();
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+        for row in padded_pixels.chunks(padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+        }
+        drop(padded_pixels);
+        read_back_buffer.unmap();
+
+        Ok(pixels)
+    }
+}
+
+fn create_offscreen_texture(device: &wgpu::Device, size: (u32, u32), format: wgpu::TextureFormat) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("offscreen target texture"),
+        size: wgpu::Extent3d {
+            width: size.0, height: size.1, depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[format],
+    })
+}
@@ -0,0 +1,137 @@
+
+
+/// Presets of raymarching settings, from the fastest to the most precise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaymarchQuality {
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+
+/// How hard the raymarcher tries to hit the surface of the objects.
+/// Set for the whole renderer, and can be overridden per object.
+///
+/// The stacks the raymarcher evaluates trees with have a fixed size, which is not a setting: it holds 8 values
+/// and 8 nested domain operations. Loading a deeper tree fails with [`crate::error::MorpheusError::CsgTooDeep`].
+/// Operands are evaluated deepest first, so trees of less than 256 leaves always fit the value stack.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaymarchSettings {
+    pub(crate) max_iterations: u32,
+    pub(crate) hit_epsilon: f32,
+    pub(crate) max_distance: f32,
+    pub(crate) normal_epsilon: f32,
+    pub(crate) over_relaxation: f32,
+}
+
+impl RaymarchSettings {
+    pub fn preset(quality: RaymarchQuality) -> RaymarchSettings {
+        match quality {
+            RaymarchQuality::Low => RaymarchSettings {
+                max_iterations: 64,
+                hit_epsilon: 0.002,
+                max_distance: 100.0,
+                normal_epsilon: 0.001,
+                over_relaxation: 1.6,
+            },
+            RaymarchQuality::Medium => RaymarchSettings {
+                max_iterations: 128,
+                hit_epsilon: 0.0005,
+                max_distance: 500.0,
+                normal_epsilon: 0.0005,
+                over_relaxation: 1.5,
+            },
+            RaymarchQuality::High => RaymarchSettings {
+                max_iterations: 200,
+                hit_epsilon: 0.0001,
+                max_distance: 1000.0,
+                normal_epsilon: 0.0001,
+                over_relaxation: 1.4,
+            },
+            RaymarchQuality::Ultra => RaymarchSettings {
+                max_iterations: 400,
+                hit_epsilon: 0.00003,
+                max_distance: 5000.0,
+                normal_epsilon: 0.00005,
+                over_relaxation: 1.2,
+            },
+        }
+    }
+
+    /// Most steps taken along a ray before giving up on it.
+    pub fn max_iterations(self, max_iterations: u32) -> RaymarchSettings {
+        RaymarchSettings {
+            max_iterations,
+            ..self
+        }
+    }
+
+    /// How close to the surface a ray needs to get to hit it, near the camera.
+    /// Farther away, rays stop as soon as the surface is within the footprint of their pixel,
+    /// where more precision can't be seen anyway.
+    pub fn hit_epsilon(self, hit_epsilon: f32) -> RaymarchSettings {
+        RaymarchSettings {
+            hit_epsilon,
+            ..self
+        }
+    }
+
+    /// Distance from the camera after which rays stop.
+    pub fn max_distance(self, max_distance: f32) -> RaymarchSettings {
+        RaymarchSettings {
+            max_distance,
+            ..self
+        }
+    }
+
+    /// Step used to differentiate the tree where the gradients of its operands cancel out,
+    /// on creases or in smooth blends. Elsewhere normals are computed analytically.
+    pub fn normal_epsilon(self, normal_epsilon: f32) -> RaymarchSettings {
+        RaymarchSettings {
+            normal_epsilon,
+            ..self
+        }
+    }
+
+    /// How much further than the distance to the surface each step goes, between 1 and 2.
+    /// Larger steps get through empty space faster, a step that went over the surface is taken again without it.
+    /// 1 is plain sphere tracing.
+    pub fn over_relaxation(self, over_relaxation: f32) -> RaymarchSettings {
+        RaymarchSettings {
+            over_relaxation: over_relaxation.clamp(1.0, 2.0),
+            ..self
+        }
+    }
+
+    pub(crate) fn to_gpu(self) -> RaymarchSettingsToGpu {
+        RaymarchSettingsToGpu {
+            max_iterations: self.max_iterations,
+            hit_epsilon: self.hit_epsilon,
+            max_distance: self.max_distance,
+            normal_epsilon: self.normal_epsilon,
+            over_relaxation: self.over_relaxation,
+            _padding: [0.0; 3],
+        }
+    }
+}
+
+impl Default for RaymarchSettings {
+    fn default() -> Self {
+        RaymarchSettings::preset(RaymarchQuality::High)
+    }
+}
+
+/// Settings as read by the raymarcher.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RaymarchSettingsToGpu {
+    max_iterations: u32,
+    hit_epsilon: f32,
+    max_distance: f32,
+    normal_epsilon: f32,
+    over_relaxation: f32,
+    _padding: [f32; 3],
+}
+
+unsafe impl bytemuck::Zeroable for RaymarchSettingsToGpu {}
+unsafe impl bytemuck::Pod for RaymarchSettingsToGpu {}
@@ -0,0 +1,121 @@
+
+
+/// Description of how the renderer should pick and set up the gpu.
+/// Any value that is not set falls back on a sensible default.
+#[derive(Debug, Clone)]
+pub struct RendererDescriptor {
+    pub(crate) backends: Option<wgpu::Backends>,
+    pub(crate) power_preference: wgpu::PowerPreference,
+    pub(crate) force_fallback_adapter: bool,
+    pub(crate) present_mode: Option<wgpu::PresentMode>,
+    pub(crate) alpha_mode: Option<wgpu::CompositeAlphaMode>,
+    pub(crate) features: wgpu::Features,
+    pub(crate) limits: wgpu::Limits,
+    pub(crate) raymarch_settings: super::raymarch_settings::RaymarchSettings,
+    pub(crate) cone_prepass: bool,
+}
+
+impl RendererDescriptor {
+    pub fn new() -> RendererDescriptor {
+        RendererDescriptor {
+            backends: None,
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            force_fallback_adapter: false,
+            present_mode: None,
+            alpha_mode: None,
+            features: wgpu::Features::default(),
+            limits: wgpu::Limits::default(),
+            raymarch_settings: Default::default(),
+            cone_prepass: false,
+        }
+    }
+
+    /// Backends wgpu is allowed to use.
+    /// Defaults to GL for window renderers and to any backend for headless ones.
+    pub fn backends(self, backends: wgpu::Backends) -> RendererDescriptor {
+        RendererDescriptor {
+            backends: Some(backends),
+            ..self
+        }
+    }
+
+    pub fn power_preference(self, power_preference: wgpu::PowerPreference) -> RendererDescriptor {
+        RendererDescriptor {
+            power_preference,
+            ..self
+        }
+    }
+
+    /// Only accept a software adapter. This allows to render on machines without gpu.
+    pub fn force_fallback_adapter(self, force_fallback_adapter: bool) -> RendererDescriptor {
+        RendererDescriptor {
+            force_fallback_adapter,
+            ..self
+        }
+    }
+
+    /// Present mode of the window surface.
+    /// Defaults to the first present mode supported by the surface.
+    pub fn present_mode(self, present_mode: wgpu::PresentMode) -> RendererDescriptor {
+        RendererDescriptor {
+            present_mode: Some(present_mode),
+            ..self
+        }
+    }
+
+    /// Shortcut to select a present mode that is always supported, with or without vsync.
+    pub fn vsync(self, vsync: bool) -> RendererDescriptor {
+        let present_mode = match vsync {
+            true => wgpu::PresentMode::AutoVsync,
+            false => wgpu::PresentMode::AutoNoVsync,
+        };
+        self.present_mode(present_mode)
+    }
+
+    /// Alpha mode of the window surface.
+    /// Defaults to the first alpha mode supported by the surface.
+    pub fn alpha_mode(self, alpha_mode: wgpu::CompositeAlphaMode) -> RendererDescriptor {
+        RendererDescriptor {
+            alpha_mode: Some(alpha_mode),
+            ..self
+        }
+    }
+
+    /// Features the device is required to have.
+    pub fn features(self, features: wgpu::Features) -> RendererDescriptor {
+        RendererDescriptor {
+            features,
+            ..self
+        }
+    }
+
+    /// Limits the device is required to support.
+    pub fn limits(self, limits: wgpu::Limits) -> RendererDescriptor {
+        RendererDescriptor {
+            limits,
+            ..self
+        }
+    }
+
+    /// Raymarching settings the renderer starts with. They can be changed later on.
+    pub fn raymarch_settings(self, raymarch_settings: super::raymarch_settings::RaymarchSettings) -> RendererDescriptor {
+        RendererDescriptor {
+            raymarch_settings,
+            ..self
+        }
+    }
+
+    /// Start with the cone marching prepass enabled. It can be toggled later on.
+    pub fn cone_prepass(self, cone_prepass: bool) -> RendererDescriptor {
+        RendererDescriptor {
+            cone_prepass,
+            ..self
+        }
+    }
+}
+
+impl Default for RendererDescriptor {
+    fn default() -> Self {
+        RendererDescriptor::new()
+    }
+}
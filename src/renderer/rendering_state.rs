@@ -1,94 +1,408 @@
-use super::{deferred_renderer::DeferredRenderer, asset_manager::AssetManager};
+use super::{deferred_renderer::{DeferredRenderer, ITERATIONS_FORMAT}, asset_manager::AssetManager, offscreen_target::OffscreenTarget};
+use super::renderer_descriptor::RendererDescriptor;
+use crate::error::MorpheusError;
 
 
+/// Where the frames rendered by the deferred renderer end up.
+pub(crate) enum RenderTarget {
+    /// Window surface, the frame is presented after each render.
+    Surface {
+        surface: wgpu::Surface,
+        config: wgpu::SurfaceConfiguration,
+    },
+    /// Owned texture, the last frame can be read back.
+    Offscreen(OffscreenTarget),
+    /// The host application owns the device and provides the target view on each render.
+    External,
+}
+
 /// WGPU stuff. Includes unsafe references to the created surface,
 /// so we need to be careful with this.
 /// I would like to involve a lifetime in there, but then I have more issues in examples.
 /// I'll figure it out later.
 /// Maybe a Renderer generic over the surface, and provide a way to get it back when needed?
 pub(crate) struct RenderingState {
-    pub(crate) surface: wgpu::Surface,
-    pub(crate) device: wgpu::Device,
-    pub(crate) queue: wgpu::Queue,
-    pub(crate) config: wgpu::SurfaceConfiguration,
+    pub(crate) target: RenderTarget,
+    /// Shared, as the device may belong to a host application.
+    pub(crate) device: std::sync::Arc<wgpu::Device>,
+    pub(crate) queue: std::sync::Arc<wgpu::Queue>,
     pub(crate) size: (u32, u32),
     pub(crate) renderer: DeferredRenderer,
 }
 
 impl RenderingState {
-    pub(crate) fn new<T>(handle: &T, start_size: (u32, u32)) -> Result<RenderingState, crate::error::MorpheusError>
+    pub(crate) fn new<T>(handle: &T, start_size: (u32, u32), descriptor: &RendererDescriptor) -> Result<RenderingState, MorpheusError>
         where T: raw_window_handle::HasRawWindowHandle + raw_window_handle::HasRawDisplayHandle,
     {
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::GL, // any other does not work yet for me :( 
+            backends: descriptor.backends.unwrap_or(wgpu::Backends::GL), // any other does not work yet for me :(
             ..Default::default()
         });
-    
+
         let surface = unsafe { instance.create_surface(handle) }.unwrap();
         let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::HighPerformance,
+            power_preference: descriptor.power_preference,
             compatible_surface: Some(&surface),
-            force_fallback_adapter: false,
-        })).ok_or(crate::error::MorpheusError::NoAvailableAdapter)?;
-        
-        let (device, queue) = pollster::block_on(adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                features: wgpu::Features::default(),
-                limits: wgpu::Limits::default(),
-                label: None,
-            },
-            None, // Trace path
-        ))?;
-    
+            force_fallback_adapter: descriptor.force_fallback_adapter,
+        })).ok_or(MorpheusError::NoAvailableAdapter)?;
+
+        let (device, queue) = request_device(&adapter, descriptor)?;
+
         let surface_caps = surface.get_capabilities(&adapter);
         // Shader code in this tutorial assumes an sRGB surface texture. Using a different
         // one will result all the colors coming out darker. If you want to support non
         // sRGB surfaces, you'll need to account for that when drawing to the frame.
         let surface_format = surface_caps.formats.iter()
             .copied()
-            .find(|f| f.is_srgb())            
+            .find(|f| f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
 
+        let present_mode = match descriptor.present_mode {
+            // auto modes fall back on supported ones
+            Some(mode @ (wgpu::PresentMode::AutoVsync | wgpu::PresentMode::AutoNoVsync)) => mode,
+            Some(mode) if surface_caps.present_modes.contains(&mode) => mode,
+            Some(mode) => return Err(MorpheusError::UnsupportedPresentMode(mode)),
+            None => surface_caps.present_modes[0],
+        };
+
+        let alpha_mode = match descriptor.alpha_mode {
+            Some(wgpu::CompositeAlphaMode::Auto) => wgpu::CompositeAlphaMode::Auto,
+            Some(mode) if surface_caps.alpha_modes.contains(&mode) => mode,
+            Some(mode) => return Err(MorpheusError::UnsupportedAlphaMode(mode)),
+            None => surface_caps.alpha_modes[0],
+        };
+
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
             width: start_size.0,
             height: start_size.1,
-            present_mode: surface_caps.present_modes[0],
-            alpha_mode: surface_caps.alpha_modes[0],
+            present_mode,
+            alpha_mode,
             view_formats: vec![],
         };
 
         surface.configure(&device, &config);
 
-        let renderer = DeferredRenderer::new(&device, &config);
+        let renderer = DeferredRenderer::new(&device, config.format, start_size, descriptor.raymarch_settings, descriptor.cone_prepass);
 
         Ok(RenderingState {
-            surface,
-            device,
-            queue,
-            config,
+            target: RenderTarget::Surface { surface, config },
+            device: std::sync::Arc::new(device),
+            queue: std::sync::Arc::new(queue),
             size: start_size,
             renderer,
         })
     }
 
+    pub(crate) fn new_headless(size: (u32, u32), descriptor: &RendererDescriptor) -> Result<RenderingState, MorpheusError> {
+        // without surface, we are not limited by the windowing system backend
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: descriptor.backends.unwrap_or(wgpu::Backends::all()),
+            ..Default::default()
+        });
+
+        // prefer a real gpu, but fall back on a software adapter so we can run on machines without any
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: descriptor.power_preference,
+            compatible_surface: None,
+            force_fallback_adapter: descriptor.force_fallback_adapter,
+        })).or_else(|| pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: descriptor.power_preference,
+            compatible_surface: None,
+            force_fallback_adapter: true,
+        }))).ok_or(MorpheusError::NoAvailableAdapter)?;
+
+        let (device, queue) = request_device(&adapter, descriptor)?;
+
+        let target = OffscreenTarget::new(&device, size);
+        let renderer = DeferredRenderer::new(&device, super::offscreen_target::OFFSCREEN_FORMAT, size, descriptor.raymarch_settings, descriptor.cone_prepass);
+
+        Ok(RenderingState {
+            target: RenderTarget::Offscreen(target),
+            device: std::sync::Arc::new(device),
+            queue: std::sync::Arc::new(queue),
+            size,
+            renderer,
+        })
+    }
+
+    /// Create a state on top of a device created by a host application.
+    /// The output of the renderer will be written into views of the given format.
+    pub(crate) fn with_device(device: std::sync::Arc<wgpu::Device>, queue: std::sync::Arc<wgpu::Queue>, target_format: wgpu::TextureFormat, size: (u32, u32), descriptor: &RendererDescriptor) -> RenderingState {
+        let renderer = DeferredRenderer::new(&device, target_format, size, descriptor.raymarch_settings, descriptor.cone_prepass);
+
+        RenderingState {
+            target: RenderTarget::External,
+            device,
+            queue,
+            size,
+            renderer,
+        }
+    }
+
     pub(crate) fn resize(&mut self, new_size: (u32, u32)) {
         if new_size.0 > 0 && new_size.1 > 0 {
             self.renderer.resize(&self.device, &self.queue, new_size);
             self.size = new_size;
-            self.config.width = new_size.0;
-            self.config.height = new_size.1;
-            self.surface.configure(&self.device, &self.config);
+            match &mut self.target {
+                RenderTarget::Surface { surface, config } => {
+                    config.width = new_size.0;
+                    config.height = new_size.1;
+                    surface.configure(&self.device, config);
+                },
+                RenderTarget::Offscreen(target) => target.resize(&self.device, new_size),
+                RenderTarget::External => { /* the host owns the target */ },
+            }
         }
     }
 
-    pub(crate) fn update_uniforms(&mut self, world: &mut crate::world::World) {
-        self.renderer.update_uniforms(world, &self.queue)
+    pub(crate) fn update_uniforms(&mut self, world: &mut crate::world::World, assets: &AssetManager) {
+        self.renderer.update_uniforms(world, assets, &self.device, &self.queue)
+    }
+
+    pub(crate) fn render(&self, world: &crate::world::World, assets: &AssetManager) -> Result<(), MorpheusError> {
+        match &self.target {
+            RenderTarget::Surface { surface, config } => {
+                let output = match acquire_frame(&ConfiguredSurface { surface, device: &self.device, config })? {
+                    Some(output) => output,
+                    // no frame available right now, skip this one
+                    None => return Ok(()),
+                };
+                let output_view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+                self.submit_frame(world, assets, &output_view);
+                output.present();
+            },
+            RenderTarget::Offscreen(target) => self.submit_frame(world, assets, &target.view()),
+            // nothing to render into, the host should call render_to
+            RenderTarget::External => return Err(MorpheusError::NoRenderTarget),
+        }
+        Ok(())
+    }
+
+    fn submit_frame(&self, world: &crate::world::World, assets: &AssetManager, output_view: &wgpu::TextureView) {
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("deferred renderer encoder"),
+        });
+
+        self.renderer.render(world, assets, &mut encoder, output_view);
+
+        // submit will accept anything that implements IntoIter
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    pub(crate) fn render_to(&self, world: &crate::world::World, assets: &AssetManager, output_view: &wgpu::TextureView, encoder: &mut wgpu::CommandEncoder) {
+        self.renderer.render(world, assets, encoder, output_view);
     }
 
-    pub(crate) fn render(&self, world: &crate::world::World, assets: &AssetManager) -> Result<(), wgpu::SurfaceError> {
-        self.renderer.render(world, assets, &self.device, &self.queue, &self.surface)
+    /// Render the number of raymarching iterations of each pixel and read them back, if we are rendering offscreen.
+    pub(crate) fn read_iterations(&self, world: &crate::world::World, assets: &AssetManager) -> Result<Vec<u32>, MorpheusError> {
+        let RenderTarget::Offscreen(_) = &self.target else {
+            return Err(MorpheusError::NotHeadless);
+        };
+
+        let target = OffscreenTarget::with_format(&self.device, self.size, ITERATIONS_FORMAT);
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("iterations encoder"),
+        });
+        self.renderer.render_iterations(&self.device, world, assets, &mut encoder, &target.view());
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let counts = target.read_pixels(&self.device, &self.queue)?;
+        Ok(counts.chunks(2)
+            .map(|half| f16_to_f32(u16::from_ne_bytes([half[0], half[1]])) as u32)
+            .collect())
     }
 
-}
\ No newline at end of file
+    pub(crate) fn read_pass_timings(&self) -> Result<Option<super::PassTimings>, MorpheusError> {
+        self.renderer.read_pass_timings(&self.device, &self.queue)
+    }
+
+    /// Read back the last rendered frame, if we are rendering offscreen.
+    pub(crate) fn read_frame(&self) -> Result<Vec<u8>, MorpheusError> {
+        match &self.target {
+            RenderTarget::Offscreen(target) => target.read_pixels(&self.device, &self.queue),
+            RenderTarget::Surface { .. } | RenderTarget::External => Err(MorpheusError::NotHeadless),
+        }
+    }
+
+}
+
+/// Decode a half float, as read back from a target.
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32 / 1024.0;
+    match exponent {
+        // subnormals
+        0 => sign * mantissa * 2.0f32.powi(-14),
+        31 if mantissa == 0.0 => sign * f32::INFINITY,
+        31 => f32::NAN,
+        _ => sign * (1.0 + mantissa) * 2.0f32.powi(exponent - 15),
+    }
+}
+
+/// Source of the frames to render into, a surface in practice.
+/// This allows to test how we recover from surface errors without any window.
+trait FrameSource {
+    type Frame;
+    fn next_frame(&self) -> Result<Self::Frame, wgpu::SurfaceError>;
+    /// Configure the source again, after it was lost or outdated.
+    fn reconfigure(&self);
+}
+
+/// Surface along with what is needed to configure it again.
+struct ConfiguredSurface<'a> {
+    surface: &'a wgpu::Surface,
+    device: &'a wgpu::Device,
+    config: &'a wgpu::SurfaceConfiguration,
+}
+
+impl<'a> FrameSource for ConfiguredSurface<'a> {
+    type Frame = wgpu::SurfaceTexture;
+
+    fn next_frame(&self) -> Result<wgpu::SurfaceTexture, wgpu::SurfaceError> {
+        self.surface.get_current_texture()
+    }
+
+    fn reconfigure(&self) {
+        self.surface.configure(self.device, self.config);
+    }
+}
+
+/// Get the next frame of the source.
+/// Lost and outdated sources are reconfigured, and we retry once.
+/// Returns None if the frame should be skipped, and only errors if the source can't be recovered at all.
+fn acquire_frame<S: FrameSource>(source: &S) -> Result<Option<S::Frame>, MorpheusError> {
+    match source.next_frame() {
+        Ok(output) => Ok(Some(output)),
+        Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+            source.reconfigure();
+            match source.next_frame() {
+                Ok(output) => Ok(Some(output)),
+                Err(wgpu::SurfaceError::OutOfMemory) => Err(MorpheusError::OutOfMemory),
+                // still unusable, the next frame will try again
+                Err(_) => Ok(None),
+            }
+        },
+        // the frame took too long to come, should be resolved by the next one
+        Err(wgpu::SurfaceError::Timeout) => Ok(None),
+        Err(wgpu::SurfaceError::OutOfMemory) => Err(MorpheusError::OutOfMemory),
+    }
+}
+
+/// Request a device with the features and limits of the descriptor,
+/// and fail early with a meaningful error if the adapter can't provide them.
+fn request_device(adapter: &wgpu::Adapter, descriptor: &RendererDescriptor) -> Result<(wgpu::Device, wgpu::Queue), MorpheusError> {
+    let missing_features = descriptor.features - adapter.features();
+    if !missing_features.is_empty() {
+        return Err(MorpheusError::UnsupportedFeatures(missing_features));
+    }
+
+    let mut unsupported_limit = None;
+    descriptor.limits.check_limits_with_fail_fn(&adapter.limits(), true, |name, requested, allowed| {
+        unsupported_limit = Some(MorpheusError::UnsupportedLimit { name, requested, allowed });
+    });
+    if let Some(error) = unsupported_limit {
+        return Err(error);
+    }
+
+    Ok(pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            features: descriptor.features,
+            limits: descriptor.limits.clone(),
+            label: None,
+        },
+        None, // Trace path
+    ))?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Gives out the queued results, and counts the reconfigurations.
+    struct FakeSurface {
+        results: RefCell<VecDeque<Result<u32, wgpu::SurfaceError>>>,
+        reconfigured: Cell<u32>,
+    }
+
+    impl FakeSurface {
+        fn new(results: Vec<Result<u32, wgpu::SurfaceError>>) -> FakeSurface {
+            FakeSurface {
+                results: RefCell::new(results.into()),
+                reconfigured: Cell::new(0),
+            }
+        }
+    }
+
+    impl FrameSource for FakeSurface {
+        type Frame = u32;
+
+        fn next_frame(&self) -> Result<u32, wgpu::SurfaceError> {
+            self.results.borrow_mut().pop_front().expect("more frames requested than expected")
+        }
+
+        fn reconfigure(&self) {
+            self.reconfigured.set(self.reconfigured.get() + 1);
+        }
+    }
+
+    #[test]
+    fn available_frame_is_used() {
+        let surface = FakeSurface::new(vec![Ok(1)]);
+        assert!(matches!(acquire_frame(&surface), Ok(Some(1))));
+        assert_eq!(surface.reconfigured.get(), 0);
+    }
+
+    #[test]
+    fn lost_and_outdated_surfaces_are_reconfigured_and_retried() {
+        for error in [wgpu::SurfaceError::Lost, wgpu::SurfaceError::Outdated] {
+            let surface = FakeSurface::new(vec![Err(error), Ok(2)]);
+            assert!(matches!(acquire_frame(&surface), Ok(Some(2))));
+            assert_eq!(surface.reconfigured.get(), 1);
+        }
+    }
+
+    #[test]
+    fn surface_still_unusable_after_reconfiguration_skips_the_frame() {
+        for error in [wgpu::SurfaceError::Lost, wgpu::SurfaceError::Outdated, wgpu::SurfaceError::Timeout] {
+            let surface = FakeSurface::new(vec![Err(wgpu::SurfaceError::Lost), Err(error)]);
+            assert!(matches!(acquire_frame(&surface), Ok(None)));
+            assert_eq!(surface.reconfigured.get(), 1);
+        }
+    }
+
+    #[test]
+    fn timeout_skips_the_frame() {
+        let surface = FakeSurface::new(vec![Err(wgpu::SurfaceError::Timeout)]);
+        assert!(matches!(acquire_frame(&surface), Ok(None)));
+        assert_eq!(surface.reconfigured.get(), 0);
+    }
+
+    #[test]
+    fn only_out_of_memory_is_an_error() {
+        let surface = FakeSurface::new(vec![Err(wgpu::SurfaceError::OutOfMemory)]);
+        assert!(matches!(acquire_frame(&surface), Err(MorpheusError::OutOfMemory)));
+        assert_eq!(surface.reconfigured.get(), 0);
+
+        let surface = FakeSurface::new(vec![Err(wgpu::SurfaceError::Outdated), Err(wgpu::SurfaceError::OutOfMemory)]);
+        assert!(matches!(acquire_frame(&surface), Err(MorpheusError::OutOfMemory)));
+        assert_eq!(surface.reconfigured.get(), 1);
+    }
+
+    #[test]
+    fn half_floats_decode_the_iteration_counts() {
+        assert_eq!(f16_to_f32(0x0000), 0.0);
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0x5640), 100.0);
+        assert_eq!(f16_to_f32(0x6800), 2048.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x0001), 2.0f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+    }
+}
@@ -22,10 +22,10 @@ impl BufferElem for ScreenResolution {
     };
     #[cfg(debug_assertions)]
     const LABEL: &'static str = "screen resolution";
-    const VISIBILITY: wgpu::ShaderStages = wgpu::ShaderStages::FRAGMENT;
+    const VISIBILITY: wgpu::ShaderStages = wgpu::ShaderStages::VERTEX_FRAGMENT;
     const SIZE: u64 = SCREEN_RESOLUTION_SIZE as u64;
     fn to_bytes(&self) -> &[u8] {
-        bytemuck::cast_ref::<ScreenResolution, [u8; SCREEN_RESOLUTION_SIZE]>(&self)
+        bytemuck::cast_ref::<ScreenResolution, [u8; SCREEN_RESOLUTION_SIZE]>(self)
     }
 }
 
@@ -0,0 +1,126 @@
+/// Tree of signed distance functions, as rendered by the raymarcher.
+/// Trees built with the csg crate can be used as they are, and combined with
+/// the primitives and operations the raymarcher supports on top of them.
+pub enum Sdf {
+    /// Tree of the csg crate: spheres, cubes, and hard boolean operations between them.
+    Csg(csg::CSG),
+    Primitive(Primitive),
+    /// Operation between the first and the second tree. Differences carve the second out of the first.
+    Operation(Operation, Box<Sdf>, Box<Sdf>),
+    /// Tree evaluated in a changed space.
+    Domain(DomainOp, Box<Sdf>),
+}
+
+impl Sdf {
+    pub fn union(self, other: impl Into<Sdf>) -> Sdf {
+        Sdf::Operation(Operation::Union, Box::new(self), Box::new(other.into()))
+    }
+
+    pub fn inter(self, other: impl Into<Sdf>) -> Sdf {
+        Sdf::Operation(Operation::Inter, Box::new(self), Box::new(other.into()))
+    }
+
+    /// Carve the other tree out of this one.
+    pub fn diff(self, other: impl Into<Sdf>) -> Sdf {
+        Sdf::Operation(Operation::Diff, Box::new(self), Box::new(other.into()))
+    }
+
+    /// Union blending the surfaces where they are closer than the radius.
+    pub fn smooth_union(self, other: impl Into<Sdf>, radius: f32) -> Sdf {
+        Sdf::Operation(Operation::SmoothUnion { radius }, Box::new(self), Box::new(other.into()))
+    }
+
+    pub fn smooth_inter(self, other: impl Into<Sdf>, radius: f32) -> Sdf {
+        Sdf::Operation(Operation::SmoothInter { radius }, Box::new(self), Box::new(other.into()))
+    }
+
+    pub fn smooth_diff(self, other: impl Into<Sdf>, radius: f32) -> Sdf {
+        Sdf::Operation(Operation::SmoothDiff { radius }, Box::new(self), Box::new(other.into()))
+    }
+
+    /// Repeat the tree forever, on the axes with a non zero period.
+    pub fn repeated(self, period: glam::Vec3) -> Sdf {
+        Sdf::Domain(DomainOp::Repeat { period }, Box::new(self))
+    }
+
+    /// Repeat the tree count times on each side of the original, on the axes with a non zero period.
+    pub fn repeated_limited(self, period: glam::Vec3, count: glam::Vec3) -> Sdf {
+        Sdf::Domain(DomainOp::RepeatLimited { period, count }, Box::new(self))
+    }
+
+    /// Reflect the positive side of the tree on the negative side, for each mirrored axis.
+    pub fn mirrored(self, x: bool, y: bool, z: bool) -> Sdf {
+        Sdf::Domain(DomainOp::Mirror { x, y, z }, Box::new(self))
+    }
+
+    /// Twist the tree around the y axis, by rate radians per unit along y.
+    pub fn twisted(self, rate: f32) -> Sdf {
+        Sdf::Domain(DomainOp::Twist { rate }, Box::new(self))
+    }
+
+    /// Bend the tree around the z axis, by rate radians per unit along x.
+    pub fn bent(self, rate: f32) -> Sdf {
+        Sdf::Domain(DomainOp::Bend { rate }, Box::new(self))
+    }
+
+    /// Scale the tree, then rotate it and move it by the translation.
+    pub fn transformed(self, translation: glam::Vec3, rotation: glam::Quat, scale: glam::Vec3) -> Sdf {
+        Sdf::Domain(DomainOp::Transform { translation, rotation, scale }, Box::new(self))
+    }
+}
+
+impl From<csg::CSG> for Sdf {
+    fn from(csg: csg::CSG) -> Sdf {
+        Sdf::Csg(csg)
+    }
+}
+
+impl From<Primitive> for Sdf {
+    fn from(primitive: Primitive) -> Sdf {
+        Sdf::Primitive(primitive)
+    }
+}
+
+/// Shapes the raymarcher knows on top of the spheres and cubes of the csg crate.
+/// Placed primitives are rotated by the quaternion, then moved to the offset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Primitive {
+    /// Ring in the xz plane.
+    Torus { offset: glam::Vec3, rotation: glam::Quat, major_radius: f32, minor_radius: f32 },
+    /// Capped cylinder along the y axis.
+    Cylinder { offset: glam::Vec3, rotation: glam::Quat, radius: f32, half_height: f32 },
+    /// Segment along the y axis, inflated by the radius.
+    Capsule { offset: glam::Vec3, rotation: glam::Quat, radius: f32, half_height: f32 },
+    /// Cone along the y axis, with the base at the bottom.
+    Cone { offset: glam::Vec3, rotation: glam::Quat, radius: f32, half_height: f32 },
+    /// Half space of the points p with dot(p, normal) <= distance.
+    Plane { normal: glam::Vec3, distance: f32 },
+    Ellipsoid { offset: glam::Vec3, rotation: glam::Quat, radii: glam::Vec3 },
+    /// Box of the given half extents, with the edges rounded by the radius inside of it.
+    RoundedBox { offset: glam::Vec3, rotation: glam::Quat, size: glam::Vec3, radius: f32 },
+    /// Hexagonal prism along the y axis, the radius goes to the flat sides.
+    HexPrism { offset: glam::Vec3, rotation: glam::Quat, radius: f32, half_height: f32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Union,
+    Inter,
+    Diff,
+    /// Operations blending the two surfaces over the radius, a radius of zero gives the hard operation.
+    SmoothUnion { radius: f32 },
+    SmoothInter { radius: f32 },
+    SmoothDiff { radius: f32 },
+}
+
+/// Changes of the space a subtree is evaluated in.
+/// Twists, bends and scales stretch space, the distance is scaled back so the raymarcher does not overshoot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DomainOp {
+    Repeat { period: glam::Vec3 },
+    RepeatLimited { period: glam::Vec3, count: glam::Vec3 },
+    Mirror { x: bool, y: bool, z: bool },
+    Twist { rate: f32 },
+    Bend { rate: f32 },
+    Transform { translation: glam::Vec3, rotation: glam::Quat, scale: glam::Vec3 },
+}
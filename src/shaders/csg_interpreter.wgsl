@@ -0,0 +1,250 @@
+// Evaluation of any csg tree from its nodes, appended to the raymarcher when the asset is not compiled.
+
+fn scene_sdf(at: vec3<f32>) -> f32 {
+    // the csg tree is written in reverse polish notation (suffixed)
+    // use a stack to compute the sdf
+    var stack_ptr: u32 = 0u;
+    // hard coded stack size. defines the height of the biggest tree we can compute.
+    // the more the better, but the more expensive it gets.
+    // this is MAX_STACK_DEPTH of csg_buffer.rs, assets needing deeper stacks are rejected when created.
+    var sdf_stack: array<f32, 8>;
+    // domain operations evaluate their subtree at a transformed point.
+    // they push the current point when their scope opens, and pop it back when it closes.
+    var point: vec3<f32> = at;
+    var point_ptr: u32 = 0u;
+    var point_stack: array<vec3<f32>, 8>;
+
+    for(var i: u32 = 0u; i < csg_object_count; i++) {
+
+        switch csg_objects[i].csg_id {
+            case 0u: { // id 0 is sphere, push it on the stack
+                sdf_stack[stack_ptr] = sphere_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 1u: { // id 1 is cube, push it on the stack
+                sdf_stack[stack_ptr] = cube_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 6u: { // id 6 is torus
+                sdf_stack[stack_ptr] = torus_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 7u: { // id 7 is capped cylinder
+                sdf_stack[stack_ptr] = cylinder_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 8u: { // id 8 is capsule
+                sdf_stack[stack_ptr] = capsule_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 9u: { // id 9 is cone
+                sdf_stack[stack_ptr] = cone_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 10u: { // id 10 is half space plane
+                sdf_stack[stack_ptr] = plane_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 11u: { // id 11 is ellipsoid
+                sdf_stack[stack_ptr] = ellipsoid_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 12u: { // id 12 is rounded box
+                sdf_stack[stack_ptr] = rounded_box_sdf(point, i);
+                stack_ptr += 1u;
+            }
+            case 13u: { // id 13 is hexagonal prism
+                sdf_stack[stack_ptr] = hex_prism_sdf(point, i);
+                stack_ptr += 1u;
+            }
+
+            case 3u: { // id 3 is union (min), from the two values on the stack
+                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
+                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = min(sdf1, sdf2);
+                stack_ptr -= 1u; // pop 2 push 1
+            }
+            case 4u: { // id 4 is inter (max), from the two values on the stack
+                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
+                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = max(sdf1, sdf2);
+                stack_ptr -= 1u; // pop 2 push 1
+            }
+            case 5u: { // id 5 is diff (sub), from the two values on the stack
+                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
+                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = max(-sdf1, sdf2);
+                stack_ptr -= 1u; // pop 2 push 1
+            }
+            case 14u: { // id 14 is smooth union, blend radius in the node data
+                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
+                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = smin(sdf1, sdf2, blend_radius(i));
+                stack_ptr -= 1u; // pop 2 push 1
+            }
+            case 15u: { // id 15 is smooth inter
+                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
+                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = smax(sdf1, sdf2, blend_radius(i));
+                stack_ptr -= 1u; // pop 2 push 1
+            }
+            case 16u: { // id 16 is smooth diff
+                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
+                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = smax(-sdf1, sdf2, blend_radius(i));
+                stack_ptr -= 1u; // pop 2 push 1
+            }
+            case 23u: { // id 23 is diff with the operands the other way around on the stack
+                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
+                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = max(sdf1, -sdf2);
+                stack_ptr -= 1u; // pop 2 push 1
+            }
+            case 24u: { // id 24 is smooth diff with the operands the other way around
+                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
+                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = smax(sdf1, -sdf2, blend_radius(i));
+                stack_ptr -= 1u; // pop 2 push 1
+            }
+
+            case 17u: { // id 17 is infinite repetition
+                point_stack[point_ptr] = point;
+                point_ptr += 1u;
+                point = repeat_domain(point, i);
+            }
+            case 18u: { // id 18 is limited repetition
+                point_stack[point_ptr] = point;
+                point_ptr += 1u;
+                point = repeat_limited_domain(point, i);
+            }
+            case 19u: { // id 19 is mirror
+                point_stack[point_ptr] = point;
+                point_ptr += 1u;
+                point = mirror_domain(point, i);
+            }
+            case 20u: { // id 20 is twist
+                point_stack[point_ptr] = point;
+                point_ptr += 1u;
+                point = twist_domain(point, i);
+            }
+            case 21u: { // id 21 is bend
+                point_stack[point_ptr] = point;
+                point_ptr += 1u;
+                point = bend_domain(point, i);
+            }
+            case 22u: { // id 22 is an affine transform of the subtree
+                point_stack[point_ptr] = point;
+                point_ptr += 1u;
+                point = transform_domain(point, i);
+            }
+            case 255u: { // id 255 closes the scope of the last domain operation
+                point_ptr -= 1u;
+                point = point_stack[point_ptr];
+                sdf_stack[stack_ptr - 1u] = sdf_stack[stack_ptr - 1u] / scope_stretch(point, sdf_stack[stack_ptr - 1u], i);
+            }
+
+            default: { return 0.; } // csg obj not supported, stop
+        }
+    }
+
+    // the final result is last stack value !
+    return sdf_stack[stack_ptr - 1u];
+}
+
+/// Same evaluation as scene_sdf, but every value on the stack carries its gradient along.
+/// Returns the gradient in xyz and the distance in w. This is more expensive than scene_sdf,
+/// so it is only used once the march hit, to get the exact normal.
+fn scene_sdf_gradient(at: vec3<f32>) -> vec4<f32> {
+    // same stack sizes as the interpreter
+    var stack_ptr: u32 = 0u;
+    var sdf_stack: array<vec4<f32>, 8>;
+    var point: vec3<f32> = at;
+    var point_ptr: u32 = 0u;
+    var point_stack: array<vec3<f32>, 8>;
+    // index of the node that opened each scope, to bring the gradient of the subtree back out of it
+    var scope_stack: array<u32, 8>;
+
+    for(var i: u32 = 0u; i < csg_object_count; i++) {
+
+        switch csg_objects[i].csg_id {
+            case 0u, 1u, 6u, 7u, 8u, 9u, 10u, 11u, 12u, 13u: { // primitives
+                sdf_stack[stack_ptr] = primitive_gradient(point, i);
+                stack_ptr += 1u;
+            }
+
+            case 3u: { // union, the gradient of the closest
+                let sdf1 = sdf_stack[stack_ptr - 2u];
+                let sdf2 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = select(sdf2, sdf1, sdf1.w < sdf2.w);
+                stack_ptr -= 1u;
+            }
+            case 4u: { // inter, the gradient of the furthest
+                let sdf1 = sdf_stack[stack_ptr - 2u];
+                let sdf2 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = select(sdf2, sdf1, sdf1.w > sdf2.w);
+                stack_ptr -= 1u;
+            }
+            case 5u: { // diff, negating the distance negates the gradient
+                let sdf1 = -sdf_stack[stack_ptr - 2u];
+                let sdf2 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = select(sdf2, sdf1, sdf1.w > sdf2.w);
+                stack_ptr -= 1u;
+            }
+            case 14u: { // smooth union
+                let sdf1 = sdf_stack[stack_ptr - 2u];
+                let sdf2 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = smin_gradient(sdf1, sdf2, blend_radius(i));
+                stack_ptr -= 1u;
+            }
+            case 15u: { // smooth inter
+                let sdf1 = sdf_stack[stack_ptr - 2u];
+                let sdf2 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = smax_gradient(sdf1, sdf2, blend_radius(i));
+                stack_ptr -= 1u;
+            }
+            case 16u: { // smooth diff
+                let sdf1 = -sdf_stack[stack_ptr - 2u];
+                let sdf2 = sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = smax_gradient(sdf1, sdf2, blend_radius(i));
+                stack_ptr -= 1u;
+            }
+            case 23u: { // diff, first operand below
+                let sdf1 = sdf_stack[stack_ptr - 2u];
+                let sdf2 = -sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = select(sdf2, sdf1, sdf1.w > sdf2.w);
+                stack_ptr -= 1u;
+            }
+            case 24u: { // smooth diff, first operand below
+                let sdf1 = sdf_stack[stack_ptr - 2u];
+                let sdf2 = -sdf_stack[stack_ptr - 1u];
+                sdf_stack[stack_ptr - 2u] = smax_gradient(sdf1, sdf2, blend_radius(i));
+                stack_ptr -= 1u;
+            }
+
+            case 17u, 18u, 19u, 20u, 21u, 22u: { // domain operations open a scope
+                point_stack[point_ptr] = point;
+                scope_stack[point_ptr] = i;
+                point_ptr += 1u;
+                switch csg_objects[i].csg_id {
+                    case 17u: { point = repeat_domain(point, i); }
+                    case 18u: { point = repeat_limited_domain(point, i); }
+                    case 19u: { point = mirror_domain(point, i); }
+                    case 20u: { point = twist_domain(point, i); }
+                    case 21u: { point = bend_domain(point, i); }
+                    default: { point = transform_domain(point, i); }
+                }
+            }
+            case 255u: {
+                point_ptr -= 1u;
+                point = point_stack[point_ptr];
+                let inner = sdf_stack[stack_ptr - 1u];
+                let gradient = scope_gradient(point, inner.xyz, scope_stack[point_ptr]);
+                sdf_stack[stack_ptr - 1u] = vec4(gradient, inner.w) / scope_stretch(point, inner.w, i);
+            }
+
+            default: { return vec4(0.0); } // csg obj not supported, stop
+        }
+    }
+
+    return sdf_stack[stack_ptr - 1u];
+}
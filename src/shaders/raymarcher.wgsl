@@ -13,22 +13,85 @@ var<uniform> camera: Camera;
 struct ModelTransform {
     transform: mat4x4<f32>,
     inverse_tf: mat4x4<f32>,
+    // lower bound of how much the transform scales lengths, to bring object space distances in world space
+    min_scale: f32,
 }
 
+struct RaymarchSettings {
+    max_iterations: u32,
+    // hit distance near the camera, farther away the pixel footprint takes over
+    hit_epsilon: f32,
+    max_distance: f32,
+    // step of the numeric gradient, where the analytic one vanishes
+    normal_epsilon: f32,
+    // factor applied to the steps, 1 is plain sphere tracing
+    over_relaxation: f32,
+}
+
+struct Instance {
+    model: ModelTransform,
+    settings: RaymarchSettings,
+}
+
+// transforms and settings of all entities, indexed by the gpu slot of the instance
 @group(3) @binding(0)
-var<uniform> model: ModelTransform;
+var<storage> instances: array<Instance>;
+
+// transform and settings of the instance being drawn, set at the start of each entry point
+var<private> model: ModelTransform;
+var<private> settings: RaymarchSettings;
+
+struct VertexOut {
+    @builtin(position) position: vec4<f32>,
+    @location(0) @interpolate(flat) slot: u32,
+}
+
+struct BoundingBox {
+    min: vec3<f32>,
+    max: vec3<f32>,
+}
+
+// object space bounds of the csg, the proxy cube is scaled to it
+@group(2) @binding(2)
+var<uniform> bounding_box: BoundingBox;
+
 
+@vertex
+fn vs_main(@builtin(vertex_index) in_vertex_index: u32, @location(0) slot: u32) -> VertexOut {
+    model = instances[slot].model;
+
+    // unit cube positions are in [-0.5, 0.5], remap them on the bounding box
+    let local_position = mix(bounding_box.min, bounding_box.max, proxy_cube_corner(in_vertex_index) + 0.5);
+    return VertexOut(camera.proj_view * model.transform * vec4(local_position, 1.0), slot);
+}
 
 @vertex
-fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> @builtin(position) vec4<f32> {
+fn vs_cone_prepass(@builtin(vertex_index) in_vertex_index: u32, @location(0) slot: u32) -> VertexOut {
+    model = instances[slot].model;
+
+    let corner = proxy_cube_corner(in_vertex_index);
+    let local_position = mix(bounding_box.min, bounding_box.max, corner + 0.5);
+    // tiles are only rasterized if the box covers their center, but their distance is read by all their pixels.
+    // grow the box by a couple of tiles so it covers every tile the full resolution box touches.
+    // corners close to the camera still grow by a tile at the near plane, or the margin would vanish
+    // when the camera is inside or right next to the box.
+    let world_position = (model.transform * vec4(local_position, 1.0)).xyz;
+    let camera_dist = max(length(world_position - camera.position), CAMERA_NEAR);
+    let margin = 2.0 * cone_radius() * camera_dist / model.min_scale;
+    let grown_position = local_position + sign(corner) * margin;
+    return VertexOut(camera.proj_view * model.transform * vec4(grown_position, 1.0), slot);
+}
 
+/// Corner of the proxy cube for this vertex, the cube being centered on the origin with a size of 1.
+fn proxy_cube_corner(in_vertex_index: u32) -> vec3<f32> {
     // todo : I hate this. Any way to make does arrays global ?
 
     // Cube vertices with integrated triangles - positions
+    // all faces are counter clockwise when seen from outside the cube
     var positions: array<vec4<f32>, 36> = array<vec4<f32>, 36>(
         /* Front face   */
-        vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(-0.5, -0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, -0.5, 1.0),
-        vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, -0.5, 1.0), vec4<f32>(0.5, 0.5, -0.5, 1.0),
+        vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, -0.5, 1.0), vec4<f32>(-0.5, -0.5, -0.5, 1.0),
+        vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, -0.5, 1.0),
         /* Back face    */
         vec4<f32>(-0.5, 0.5, 0.5, 1.0), vec4<f32>(-0.5, -0.5, 0.5, 1.0), vec4<f32>(0.5, -0.5, 0.5, 1.0),
         vec4<f32>(-0.5, 0.5, 0.5, 1.0), vec4<f32>(0.5, -0.5, 0.5, 1.0), vec4<f32>(0.5, 0.5, 0.5, 1.0),
@@ -36,17 +99,17 @@ fn vs_main(@builtin(vertex_index) in_vertex_index: u32) -> @builtin(position) ve
         vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(-0.5, -0.5, -0.5, 1.0), vec4<f32>(-0.5, -0.5, 0.5, 1.0),
         vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(-0.5, -0.5, 0.5, 1.0), vec4<f32>(-0.5, 0.5, 0.5, 1.0),
         /* Right face   */
-        vec4<f32>(0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, 0.5, 1.0),
-        vec4<f32>(0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, 0.5, 1.0), vec4<f32>(0.5, 0.5, 0.5, 1.0),
-        /* Bottom face  */
-        vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, 0.5, 0.5, 1.0),
-        vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, 0.5, 0.5, 1.0), vec4<f32>(-0.5, 0.5, 0.5, 1.0),
+        vec4<f32>(0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, 0.5, 1.0), vec4<f32>(0.5, -0.5, -0.5, 1.0),
+        vec4<f32>(0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, 0.5, 0.5, 1.0), vec4<f32>(0.5, -0.5, 0.5, 1.0),
         /* Top face     */
+        vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(0.5, 0.5, 0.5, 1.0), vec4<f32>(0.5, 0.5, -0.5, 1.0),
+        vec4<f32>(-0.5, 0.5, -0.5, 1.0), vec4<f32>(-0.5, 0.5, 0.5, 1.0), vec4<f32>(0.5, 0.5, 0.5, 1.0),
+        /* Bottom face  */
         vec4<f32>(-0.5, -0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, 0.5, 1.0),
         vec4<f32>(-0.5, -0.5, -0.5, 1.0), vec4<f32>(0.5, -0.5, 0.5, 1.0), vec4<f32>(-0.5, -0.5, 0.5, 1.0)
     );
 
-    return camera.proj_view * model.transform * positions[in_vertex_index];
+    return positions[in_vertex_index].xyz;
 }
 
 // frag shader
@@ -60,6 +123,16 @@ struct ScreenResolution {
 @group(1) @binding(0)
 var<uniform> screen_resolution: ScreenResolution;
 
+// side in pixels of the screen tiles of the cone marching prepass
+const CONE_TILE_SIZE: u32 = 8u;
+
+// near plane of the camera projection, see CameraToGpu::new
+const CAMERA_NEAR: f32 = 0.1;
+
+// distance along the rays of each tile known to be empty, written by the cone marching prepass
+@group(1) @binding(1)
+var cone_distances: texture_2d<f32>;
+
 struct CsgNode {
     csg_id: u32,
     data: array<f32, 11>, // hand made union thingy
@@ -73,6 +146,8 @@ struct Ray {
 struct GBufferOut {
     @location(0) albedo: vec4<f32>,
     @location(1) normal_depth: vec4<f32>,
+    // depth of the actual hit, so overlapping objects occlude each other correctly
+    @builtin(frag_depth) depth: f32,
 }
 
 @group(2) @binding(0)
@@ -95,6 +170,7 @@ fn get_ray(noramalized_frag_pos: vec2<f32>) -> Ray {
     let y = noramalized_frag_pos.y * tan_cam_fovy_halfed;
 
     // ray direction should be transformed, ignoring position (so mat3x3 will ignore position transformations)
+    // it is not normalized after that, so that distances along the ray stay in world space even when scaled
     let inverse_model_rot = mat3x3(model.inverse_tf[0].xyz, model.inverse_tf[1].xyz, model.inverse_tf[2].xyz);
     let ray_direction = inverse_model_rot * normalize(cam_forward + cam_right * x + cam_up * y);
 
@@ -104,115 +180,219 @@ fn get_ray(noramalized_frag_pos: vec2<f32>) -> Ray {
     return Ray(ray_position, ray_direction);
 }
 
-@fragment
-fn fs_main(@builtin(position) in: vec4<f32>) -> GBufferOut {
-    
+/// Result of sphere tracing the ray of a pixel.
+struct Trace {
+    hit: bool,
+    // last evaluated point, in object space
+    point: vec3<f32>,
+    iterations: u32,
+}
+
+/// Sphere trace the ray going through the pixel, inside the proxy box of the instance being drawn.
+/// Pixels whose ray has nothing to march are discarded.
+fn trace_pixel(frag_position: vec2<f32>) -> Trace {
     let screen_pos = vec2(
-        (in.x / f32(screen_resolution.width) - 0.5) * 2.0,
+        (frag_position.x / f32(screen_resolution.width) - 0.5) * 2.0,
         // y is inverted because up is +y, but on screen y goes down
-        (0.5 - in.y / f32(screen_resolution.height)) * 2.0,
+        (0.5 - frag_position.y / f32(screen_resolution.height)) * 2.0,
     );
     let ray: Ray = get_ray(screen_pos);
 
-    // if(true) { return GBufferOut(vec4(-ray.dir, 1.0), vec4(1.0)); }
-
-    // todo : advance ray towards first box encounter
-    // todo : stop condition: out of the box
-
-    // the hard number over the max numbers of iterations.
-    // the more the better quality (avoid ome artifacts when we struggle to hit the csg)
-    // but also the more expensive it gets.
-    let max_iter = 200;
-    // how close to the surface we need to be in order to hit.
-    // the less the better quality, but the more expensive.
-    let hit_eps = 0.0001;
-    var eval_point: vec3<f32> = ray.origin;
-    for(var i = 0; i < max_iter; i++) {
-        let scene_sdf = scene_sdf(eval_point);
+    // only march inside the proxy box (the bounding box drawn by the vertex shader):
+    // start at the box entry point, and stop once we went out of it.
+    let box_hit = ray_box_intersection(ray, bounding_box.min, bounding_box.max);
+    if(box_hit.x > box_hit.y) {
+        discard;
+    }
+
+    // the prepass found the rays of this tile empty up to some distance, start from there.
+    // it is cleared to 0 when disabled.
+    let tile_uv = frag_position / vec2(f32(screen_resolution.width), f32(screen_resolution.height));
+    let tile = min(vec2<u32>(tile_uv * vec2<f32>(cone_resolution())), cone_resolution() - 1u);
+    let start_dist = max(box_hit.x, textureLoad(cone_distances, tile, 0).r);
+    if(start_dist > box_hit.y) {
+        discard;
+    }
+
+    // the max number of iterations, hit distance and max distance come from the quality settings.
+    // the more iterations and the smaller the hit distance, the better the quality
+    // (avoid some artifacts when we struggle to hit the csg) but the more expensive it gets.
+
+    // radius of the cone covered by a pixel, per unit of distance from the camera.
+    // once the surface is closer than that, getting any closer won't change the pixel.
+    let pixel_radius = tan(camera.fovy * 0.5) / f32(screen_resolution.height);
+
+    // over-relaxed sphere tracing: steps are longer than the distance to the surface.
+    // if the unbounding spheres of two consecutive points don't overlap, we may have jumped
+    // over the surface, so go back and finish with plain sphere tracing.
+    var relaxation = settings.over_relaxation;
+    // distance along the ray of the current eval point, in world space
+    var ray_dist: f32 = start_dist;
+    var prev_dist: f32 = ray_dist;
+    var prev_radius: f32 = 0.0;
+    var eval_point = ray.origin + ray.dir * ray_dist;
+    var i = 0u;
+    for(; i < settings.max_iterations; i++) {
+        eval_point = ray.origin + ray.dir * ray_dist;
+        // the sdf is in object space, scale it so we never step over the surface in world space
+        let scene_sdf = scene_sdf(eval_point) * model.min_scale;
+        if(relaxation > 1.0 && abs(scene_sdf) + prev_radius < ray_dist - prev_dist) {
+            // overstep, the last safe step is the distance at the previous point
+            ray_dist = prev_dist + prev_radius;
+            relaxation = 1.0;
+            continue;
+        }
+        // far away surfaces don't need to be hit as precisely
+        let hit_eps = max(settings.hit_epsilon, pixel_radius * ray_dist);
         if(scene_sdf < hit_eps) {
             // it's a hit !
-            let albedo: vec4<f32> = vec4(1.0); // todo : materials
-            let depth = length(eval_point - camera.position);
-            let normal_depth: vec4<f32> = vec4(scene_normal(eval_point), depth);
-            return GBufferOut(albedo, normal_depth);
+            return Trace(true, eval_point, i + 1u);
+        }
+        prev_dist = ray_dist;
+        prev_radius = scene_sdf;
+        ray_dist += scene_sdf * relaxation;
+        // only the unrelaxed step is sure not to skip anything
+        let safe_dist = prev_dist + prev_radius;
+        if(safe_dist > box_hit.y || safe_dist > settings.max_distance) {
+            // out of the box or too far, nothing to hit anymore
+            return Trace(false, eval_point, i + 1u);
         }
-        eval_point += ray.dir * scene_sdf;
     }
 
-    // if(true) { return GBufferOut(vec4(1.0, 0.0 ,0.0, 1.0), vec4(1.0)); }
-
-    // infinity, discard
-    discard;
-
-}
-
-fn scene_sdf(at: vec3<f32>) -> f32 {
-    // the csg tree is written in reverse polish notation (suffixed)
-    // use a stack to compute the sdf
-    var stack_ptr: u32 = 0u;
-    // hard coded stack size. defines the height of the biggest tree we can compute.
-    // the more the better, but the more expensive it gets.
-    var sdf_stack: array<f32, 8>;
-
-    for(var i: u32 = 0u; i < csg_object_count; i++) {
-
-        switch csg_objects[i].csg_id {
-            case 0u: { // id 0 is sphere, push it on the stack
-                sdf_stack[stack_ptr] = sphere_sdf(at, i);
-                stack_ptr += 1u;
-            }
-            case 1u: { // id 1 is cube, push it on the stack
-                sdf_stack[stack_ptr] = cube_sdf(at, i);
-                stack_ptr += 1u;
-            }
-
-            case 3u: { // id 3 is union (min), from the two values on the stack
-                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
-                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
-                sdf_stack[stack_ptr - 2u] = min(sdf1, sdf2);
-                stack_ptr -= 1u; // pop 2 push 1
-            }
-            case 4u: { // id 4 is inter (max), from the two values on the stack
-                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
-                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
-                sdf_stack[stack_ptr - 2u] = max(sdf1, sdf2);
-                stack_ptr -= 1u; // pop 2 push 1
-            }
-            case 5u: { // id 5 is diff (sub), from the two values on the stack
-                let sdf1: f32 = sdf_stack[stack_ptr - 2u];
-                let sdf2: f32 = sdf_stack[stack_ptr - 1u];
-                sdf_stack[stack_ptr - 2u] = max(-sdf1, sdf2);
-                stack_ptr -= 1u; // pop 2 push 1
-            }
-
-            default: { return 0.; } // csg obj not supported, stop
+    // too many iterations
+    return Trace(false, eval_point, i);
+}
+
+@fragment
+fn fs_main(in: VertexOut) -> GBufferOut {
+    model = instances[in.slot].model;
+    settings = instances[in.slot].settings;
+
+    let trace = trace_pixel(in.position.xy);
+    if(!trace.hit) {
+        // out of the box, too far or too many iterations, discard
+        discard;
+    }
+
+    let albedo: vec4<f32> = vec4(1.0); // todo : materials
+    // eval point is in object space, put it back in world space
+    let world_hit = (model.transform * vec4(trace.point, 1.0)).xyz;
+    let depth = length(world_hit - camera.position);
+    let normal_depth: vec4<f32> = vec4(scene_normal(trace.point), depth);
+    let clip_hit = camera.proj_view * vec4(world_hit, 1.0);
+    return GBufferOut(albedo, normal_depth, clip_hit.z / clip_hit.w);
+}
+
+/// Number of iterations fs_main takes for the pixel, hit or not.
+/// Counts of the objects covering the pixel are added together by blending.
+@fragment
+fn fs_iterations(in: VertexOut) -> @location(0) f32 {
+    model = instances[in.slot].model;
+    settings = instances[in.slot].settings;
+
+    return f32(trace_pixel(in.position.xy).iterations);
+}
+
+struct ConePrepassOut {
+    @location(0) distance: f32,
+    // increases with the distance, so the closest object of the tile is kept
+    @builtin(frag_depth) depth: f32,
+}
+
+@fragment
+fn fs_cone_prepass(in: VertexOut) -> ConePrepassOut {
+    model = instances[in.slot].model;
+    settings = instances[in.slot].settings;
+
+    // one fragment per tile, the ray goes through the center of the tile
+    let resolution = vec2<f32>(cone_resolution());
+    let screen_pos = vec2(
+        (in.position.x / resolution.x - 0.5) * 2.0,
+        (0.5 - in.position.y / resolution.y) * 2.0,
+    );
+    let ray: Ray = get_ray(screen_pos);
+    let cone_radius = cone_radius();
+    // the raymarcher hits anything closer than its hit distance, see trace_pixel.
+    // the skipped part of the rays must stay further than that, or the prepass would hide grazed surfaces.
+    let pixel_radius = tan(camera.fovy * 0.5) / f32(screen_resolution.height);
+    let widening = cone_radius + pixel_radius;
+
+    // cone marching: the rays of the tile stay within cone_radius * ray_dist of the center ray.
+    // after a step t, they are within widening * ray_dist + t * (1 + widening) of the current center point
+    // once the hit distance is added, and this has to stay within the distance to the surface.
+    // stop once the steps get smaller than the cone itself, the surface is close to some of the rays.
+    var ray_dist: f32 = 0.0;
+    for(var i = 0u; i < settings.max_iterations; i++) {
+        // once the whole cone left the box, there is nothing to hit for this object
+        let margin = cone_radius * ray_dist / model.min_scale;
+        let box_exit = ray_box_intersection(ray, bounding_box.min - margin, bounding_box.max + margin).y;
+        if(ray_dist > box_exit || ray_dist > settings.max_distance) {
+            break;
+        }
+        let scene_sdf = scene_sdf(ray.origin + ray.dir * ray_dist) * model.min_scale;
+        let step = (scene_sdf - settings.hit_epsilon - widening * ray_dist) / (1.0 + widening);
+        if(step < cone_radius * ray_dist) {
+            break;
         }
+        ray_dist += step;
     }
 
-    // the final result is last stack value !
-    return sdf_stack[stack_ptr - 1u];
+    return ConePrepassOut(ray_dist, ray_dist / (ray_dist + 1.0));
+}
+
+/// Resolution of the cone marching prepass, a texel per tile of the screen.
+fn cone_resolution() -> vec2<u32> {
+    let resolution = vec2(screen_resolution.width, screen_resolution.height);
+    return max((resolution + CONE_TILE_SIZE - 1u) / CONE_TILE_SIZE, vec2(1u));
+}
+
+/// Radius of a cone around the ray through the center of a tile, containing the rays of all its pixels.
+/// The radius is per unit of distance along the ray.
+fn cone_radius() -> f32 {
+    let resolution = vec2<f32>(cone_resolution());
+    let tan_cam_fovy_halfed = tan(camera.fovy * 0.5);
+    let tan_cam_fovx_halfed = f32(screen_resolution.width) / f32(screen_resolution.height) * tan_cam_fovy_halfed;
+    // half diagonal of a tile on the plane at a distance of 1 in front of the camera
+    return length(vec2(tan_cam_fovx_halfed / resolution.x, tan_cam_fovy_halfed / resolution.y));
+}
+
+/// Slab test of the ray against an axis aligned box.
+/// Returns the distances along the ray of the entry and exit points,
+/// the entry being clamped to the ray origin. If entry > exit, the box is missed.
+fn ray_box_intersection(ray: Ray, box_min: vec3<f32>, box_max: vec3<f32>) -> vec2<f32> {
+    let inv_dir = 1.0 / ray.dir;
+    let t0 = (box_min - ray.origin) * inv_dir;
+    let t1 = (box_max - ray.origin) * inv_dir;
+    let t_near = min(t0, t1);
+    let t_far = max(t0, t1);
+    let entry = max(max(t_near.x, t_near.y), t_near.z);
+    let exit = min(min(t_far.x, t_far.y), t_far.z);
+    return vec2(max(entry, 0.0), exit);
 }
 
+// scene_sdf(at: vec3<f32>) -> f32 and scene_sdf_gradient(at: vec3<f32>) -> vec4<f32> are not defined here:
+// the renderer appends either the interpreters of csg_interpreter.wgsl, or functions compiled for the csg of the asset.
+// The gradient is in xyz and the distance in w.
 
 fn scene_normal(at: vec3<f32>) -> vec3<f32> {
-    // mmmh, not a fan of calculating the sdf 4 times
-    // another solution is to come across exact normal for every sdf node,
-    // and use another stack to compute it
-    
-    // small enough for graphic precision, yet big enough to avoid noise artifacts
-    let h: f32 = 0.00001;
-    let k: vec2<f32> = vec2(1.0, -1.0);
-    let normal: vec3<f32> = normalize(
-        k.xyy * scene_sdf( at + k.xyy * h ) + 
-        k.yyx * scene_sdf( at + k.yyx * h ) + 
-        k.yxy * scene_sdf( at + k.yxy * h ) + 
-        k.xxx * scene_sdf( at + k.xxx * h )
-    );
+    // the gradient of the sdf is the normal of the surface, in object space
+    var gradient: vec3<f32> = scene_sdf_gradient(at).xyz;
+    if(dot(gradient, gradient) < 1.0e-12) {
+        // the gradients of the operands can cancel out, on creases or in smooth blends.
+        // differentiate the whole tree there, with a tetrahedron of samples.
+        let e = vec2(1.0, -1.0) * settings.normal_epsilon;
+        gradient = e.xyy * scene_sdf(at + e.xyy)
+            + e.yyx * scene_sdf(at + e.yyx)
+            + e.yxy * scene_sdf(at + e.yxy)
+            + e.xxx * scene_sdf(at + e.xxx);
+    }
+    let normal: vec3<f32> = normalize(gradient);
     
     // the normal is in cam view space, put it back in world space?
-    let model_rot = mat3x3(model.transform[0].xyz, model.transform[1].xyz, model.transform[2].xyz);
+    // normals go through the inverse transpose, so they stay orthogonal to the surface under non uniform scale
+    let normal_mat = transpose(mat3x3(model.inverse_tf[0].xyz, model.inverse_tf[1].xyz, model.inverse_tf[2].xyz));
     let cam_rot = mat3x3(camera.inv_rot[0].xyz, camera.inv_rot[1].xyz, camera.inv_rot[2].xyz);
-    let world_normal = model_rot * cam_rot * normal;
+    let world_normal = normalize(normal_mat * cam_rot * normal);
     return world_normal;
 }
 
@@ -231,18 +411,397 @@ fn sphere_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
 
 fn cube_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
     let data: array<f32, 11> = csg_objects[csg_index].data;
-    let position: vec3<f32> = vec3(data[0], data[1], data[2]);
-    let rotation: vec4<f32> = vec4(data[3], data[4], data[5], data[6]);
     let scale: vec3<f32> = vec3(data[7], data[8], data[9]);
-    let aligned = at - position;
-    let rotated = aligned; // todo: rotate
-    let q = abs(rotated) - scale;
+    let q = abs(primitive_space(at, data)) - scale;
     return length(max(q, vec3(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0);
 }
 
+// the torus ring is in the xz plane, cylinders, capsules, cones and prisms are along y
+
+fn torus_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let major_radius = data[7];
+    let minor_radius = data[8];
+    let p = primitive_space(at, data);
+    let q = vec2(length(p.xz) - major_radius, p.y);
+    return length(q) - minor_radius;
+}
+
+fn cylinder_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let radius = data[7];
+    let half_height = data[8];
+    let p = primitive_space(at, data);
+    let d = abs(vec2(length(p.xz), p.y)) - vec2(radius, half_height);
+    return min(max(d.x, d.y), 0.0) + length(max(d, vec2(0.0)));
+}
+
+fn capsule_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let radius = data[7];
+    let half_height = data[8];
+    var p = primitive_space(at, data);
+    // distance to the segment
+    p.y -= clamp(p.y, -half_height, half_height);
+    return length(p) - radius;
+}
+
+fn cone_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    return cone_local_sdf(primitive_space(at, data), data[7], data[8]);
+}
+
+/// Base of the given radius at -half_height, tip at +half_height.
+fn cone_local_sdf(p: vec3<f32>, radius: f32, half_height: f32) -> f32 {
+    let q = vec2(length(p.xz), p.y);
+    let k1 = vec2(0.0, half_height);
+    let k2 = vec2(-radius, 2.0 * half_height);
+    let ca = vec2(q.x - min(q.x, select(0.0, radius, q.y < 0.0)), abs(q.y) - half_height);
+    let cb = q - k1 + k2 * clamp(dot(k1 - q, k2) / dot(k2, k2), 0.0, 1.0);
+    let s = select(1.0, -1.0, cb.x < 0.0 && ca.y < 0.0);
+    return s * sqrt(min(dot(ca, ca), dot(cb, cb)));
+}
+
+fn plane_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
+    // everything under the plane is inside, the normal is normalized on the cpu
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let normal: vec3<f32> = vec3(data[0], data[1], data[2]);
+    let distance = data[3];
+    return dot(at, normal) - distance;
+}
+
+fn ellipsoid_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    return ellipsoid_local_sdf(primitive_space(at, data), vec3(data[7], data[8], data[9]));
+}
+
+/// Not exact, but close enough to the surface to march on.
+fn ellipsoid_local_sdf(p: vec3<f32>, radii: vec3<f32>) -> f32 {
+    let k0 = length(p / radii);
+    let k1 = length(p / (radii * radii));
+    return k0 * (k0 - 1.0) / max(k1, 0.000001);
+}
+
+fn rounded_box_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
+    // the rounding is taken inside the half extents
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let scale: vec3<f32> = vec3(data[7], data[8], data[9]);
+    let radius = data[10];
+    let q = abs(primitive_space(at, data)) - scale + radius;
+    return length(max(q, vec3(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0) - radius;
+}
+
+fn hex_prism_sdf(at: vec3<f32>, csg_index: u32) -> f32 {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    return hex_prism_local_sdf(primitive_space(at, data), data[7], data[8]);
+}
+
+/// The radius goes from the center to the flat sides.
+fn hex_prism_local_sdf(p: vec3<f32>, radius: f32, half_height: f32) -> f32 {
+    let k = vec3(-0.8660254, 0.5, 0.57735);
+    // hexagon in the xz plane, prism along y
+    let hex = abs(p.xzy);
+    let folded = hex.xy - 2.0 * min(dot(k.xy, hex.xy), 0.0) * k.xy;
+    let d = vec2(
+        length(folded - vec2(clamp(folded.x, -k.z * radius, k.z * radius), radius)) * sign(folded.y - radius),
+        hex.z - half_height,
+    );
+    return min(max(d.x, d.y), 0.0) + length(max(d, vec2(0.0)));
+}
+
+// primitive gradients, with the distance in w
+
+fn primitive_gradient(at: vec3<f32>, csg_index: u32) -> vec4<f32> {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    switch csg_objects[csg_index].csg_id {
+        case 0u: { // sphere
+            let offset: vec3<f32> = vec3(data[0], data[1], data[2]);
+            let radius = data[3];
+            return vec4(safe_normalize(at - offset), length(at - offset) - radius);
+        }
+        case 10u: { // plane
+            let normal: vec3<f32> = vec3(data[0], data[1], data[2]);
+            return vec4(normal, dot(at, normal) - data[3]);
+        }
+        default: {
+            // placed primitives, computed in their space and rotated back
+            let rotation: vec4<f32> = vec4(data[3], data[4], data[5], data[6]);
+            let local = placed_primitive_gradient(primitive_space(at, data), csg_index);
+            return vec4(quat_rotate(rotation, local.xyz), local.w);
+        }
+    }
+}
+
+fn placed_primitive_gradient(p: vec3<f32>, csg_index: u32) -> vec4<f32> {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    switch csg_objects[csg_index].csg_id {
+        case 1u: { // cube
+            return box_gradient(p, vec3(data[7], data[8], data[9]));
+        }
+        case 12u: { // rounded box, a smaller box inflated by the radius
+            let radius = data[10];
+            let inner = box_gradient(p, vec3(data[7], data[8], data[9]) - radius);
+            return vec4(inner.xyz, inner.w - radius);
+        }
+        case 6u: { // torus
+            let major_radius = data[7];
+            let minor_radius = data[8];
+            let radial = safe_normalize(vec3(p.x, 0.0, p.z));
+            let q = vec2(length(p.xz) - major_radius, p.y);
+            let gradient = safe_normalize(radial * q.x + vec3(0.0, q.y, 0.0));
+            return vec4(gradient, length(q) - minor_radius);
+        }
+        case 7u: { // capped cylinder
+            let radius = data[7];
+            let half_height = data[8];
+            let radial = safe_normalize(vec3(p.x, 0.0, p.z));
+            return extruded_gradient(radial, length(p.xz) - radius, p.y, half_height);
+        }
+        case 8u: { // capsule, from the closest point of the segment
+            let radius = data[7];
+            let half_height = data[8];
+            let from_segment = p - vec3(0.0, clamp(p.y, -half_height, half_height), 0.0);
+            return vec4(safe_normalize(from_segment), length(from_segment) - radius);
+        }
+        case 9u: { // cone
+            return cone_gradient(p, data[7], data[8]);
+        }
+        case 11u: { // ellipsoid
+            return ellipsoid_gradient(p, vec3(data[7], data[8], data[9]));
+        }
+        default: { // hexagonal prism
+            return hex_prism_gradient(p, data[7], data[8]);
+        }
+    }
+}
+
+/// Profile in the xz plane extruded along y up to the half height, as cylinders and prisms.
+/// Takes the gradient and the signed distance of the profile, and the height of the point.
+fn extruded_gradient(lateral: vec3<f32>, lateral_distance: f32, y: f32, half_height: f32) -> vec4<f32> {
+    let vertical = vec3(0.0, select(-1.0, 1.0, y >= 0.0), 0.0);
+    let d = vec2(lateral_distance, abs(y) - half_height);
+    let outside = max(d, vec2(0.0));
+    if(max(d.x, d.y) > 0.0) {
+        let weights = outside / length(outside);
+        return vec4(lateral * weights.x + vertical * weights.y, length(outside));
+    }
+    return vec4(select(vertical, lateral, d.x > d.y), max(d.x, d.y));
+}
+
+/// Same evaluation as cone_local_sdf, from the closest of the base and the side.
+fn cone_gradient(p: vec3<f32>, radius: f32, half_height: f32) -> vec4<f32> {
+    let q = vec2(length(p.xz), p.y);
+    let k1 = vec2(0.0, half_height);
+    let k2 = vec2(-radius, 2.0 * half_height);
+    // ca is folded on the y axis, the sign of y comes back in its gradient
+    let ca = vec2(q.x - min(q.x, select(0.0, radius, q.y < 0.0)), abs(q.y) - half_height);
+    let cb = q - k1 + k2 * clamp(dot(k1 - q, k2) / dot(k2, k2), 0.0, 1.0);
+    let s = select(1.0, -1.0, cb.x < 0.0 && ca.y < 0.0);
+    let from_closest = select(cb, vec2(ca.x, ca.y * select(-1.0, 1.0, q.y >= 0.0)), dot(ca, ca) < dot(cb, cb));
+    let gradient = s * from_closest / max(length(from_closest), 0.000001);
+    let radial = safe_normalize(vec3(p.x, 0.0, p.z));
+    return vec4(safe_normalize(radial * gradient.x + vec3(0.0, gradient.y, 0.0)), s * sqrt(min(dot(ca, ca), dot(cb, cb))));
+}
+
+/// Gradient of ellipsoid_local_sdf, k0 * (k0 - 1) / k1.
+fn ellipsoid_gradient(p: vec3<f32>, radii: vec3<f32>) -> vec4<f32> {
+    let k0 = max(length(p / radii), 0.000001);
+    let k1 = max(length(p / (radii * radii)), 0.000001);
+    let dk0 = p / (radii * radii * k0);
+    let dk1 = p / (radii * radii * radii * radii * k1);
+    let gradient = ((2.0 * k0 - 1.0) * k1 * dk0 - k0 * (k0 - 1.0) * dk1) / (k1 * k1);
+    return vec4(safe_normalize(gradient), k0 * (k0 - 1.0) / k1);
+}
+
+/// Same evaluation as hex_prism_local_sdf, with the folds of the hexagon undone on the gradient.
+fn hex_prism_gradient(p: vec3<f32>, radius: f32, half_height: f32) -> vec4<f32> {
+    let k = vec3(-0.8660254, 0.5, 0.57735);
+    let hex = abs(p.xz);
+    let reflected = dot(k.xy, hex) < 0.0;
+    let folded = hex - 2.0 * min(dot(k.xy, hex), 0.0) * k.xy;
+    let from_side = folded - vec2(clamp(folded.x, -k.z * radius, k.z * radius), radius);
+    let s = sign(folded.y - radius);
+    var gradient = s * from_side / max(length(from_side), 0.000001);
+    // the fold is a reflection, its own inverse
+    gradient = select(gradient, gradient - 2.0 * dot(gradient, k.xy) * k.xy, reflected);
+    gradient *= select(vec2(-1.0), vec2(1.0), p.xz >= vec2(0.0));
+    return extruded_gradient(vec3(gradient.x, 0.0, gradient.y), length(from_side) * s, p.y, half_height);
+}
+
+/// Box of the given half extents, centered on the origin.
+fn box_gradient(p: vec3<f32>, half_extents: vec3<f32>) -> vec4<f32> {
+    let s = select(vec3(-1.0), vec3(1.0), p >= vec3(0.0));
+    let w = abs(p) - half_extents;
+    let g = max(w.x, max(w.y, w.z));
+    if(g > 0.0) {
+        // outside, from the closest point of the box
+        let q = max(w, vec3(0.0));
+        let l = length(q);
+        return vec4(s * q / l, l);
+    }
+    // inside, toward the closest face
+    var face = vec3(0.0, 0.0, 1.0);
+    if(w.x > w.y && w.x > w.z) {
+        face = vec3(1.0, 0.0, 0.0);
+    }
+    else if(w.y > w.z) {
+        face = vec3(0.0, 1.0, 0.0);
+    }
+    return vec4(s * face, g);
+}
+
+// domain operations, transforming the point their subtree is evaluated at
+
+fn repeat_domain(at: vec3<f32>, csg_index: u32) -> vec3<f32> {
+    // axes with a period of 0 are not repeated
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let period: vec3<f32> = vec3(data[0], data[1], data[2]);
+    let repeated = at - period * round(at / period);
+    return select(at, repeated, period > vec3(0.0));
+}
+
+fn repeat_limited_domain(at: vec3<f32>, csg_index: u32) -> vec3<f32> {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let period: vec3<f32> = vec3(data[0], data[1], data[2]);
+    let count: vec3<f32> = vec3(data[3], data[4], data[5]);
+    let repeated = at - period * clamp(round(at / period), -count, count);
+    return select(at, repeated, period > vec3(0.0));
+}
+
+fn mirror_domain(at: vec3<f32>, csg_index: u32) -> vec3<f32> {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let mirrored: vec3<f32> = vec3(data[0], data[1], data[2]);
+    return select(at, abs(at), mirrored > vec3(0.5));
+}
+
+fn twist_domain(at: vec3<f32>, csg_index: u32) -> vec3<f32> {
+    // rotate around y, more and more along y
+    let rate = csg_objects[csg_index].data[0];
+    let c = cos(rate * at.y);
+    let s = sin(rate * at.y);
+    return vec3(c * at.x - s * at.z, at.y, s * at.x + c * at.z);
+}
+
+fn bend_domain(at: vec3<f32>, csg_index: u32) -> vec3<f32> {
+    // rotate around z, more and more along x
+    let rate = csg_objects[csg_index].data[0];
+    let c = cos(rate * at.x);
+    let s = sin(rate * at.x);
+    return vec3(c * at.x - s * at.y, s * at.x + c * at.y, at.z);
+}
+
+fn transform_domain(at: vec3<f32>, csg_index: u32) -> vec3<f32> {
+    // laid out as primitives, followed by the scale
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let scale: vec3<f32> = vec3(data[7], data[8], data[9]);
+    return primitive_space(at, data) / scale;
+}
+
+/// Gradient outside of the scope opened by the node, from the gradient inside of it.
+/// This is the gradient multiplied by the transposed jacobian of the domain operation at the point.
+fn scope_gradient(at: vec3<f32>, gradient: vec3<f32>, csg_index: u32) -> vec3<f32> {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    switch csg_objects[csg_index].csg_id {
+        case 19u: { // mirror flips the gradient on the negative side
+            let mirrored: vec3<f32> = vec3(data[0], data[1], data[2]);
+            let flip = select(vec3(-1.0), vec3(1.0), at >= vec3(0.0));
+            return select(gradient, gradient * flip, mirrored > vec3(0.5));
+        }
+        case 20u: { // twist
+            let rate = data[0];
+            let c = cos(rate * at.y);
+            let s = sin(rate * at.y);
+            return vec3(
+                dot(vec3(c, 0.0, s), gradient),
+                dot(vec3(-rate * (s * at.x + c * at.z), 1.0, rate * (c * at.x - s * at.z)), gradient),
+                dot(vec3(-s, 0.0, c), gradient),
+            );
+        }
+        case 21u: { // bend
+            let rate = data[0];
+            let c = cos(rate * at.x);
+            let s = sin(rate * at.x);
+            return vec3(
+                dot(vec3(c - rate * (s * at.x + c * at.y), s + rate * (c * at.x - s * at.y), 0.0), gradient),
+                dot(vec3(-s, c, 0.0), gradient),
+                gradient.z,
+            );
+        }
+        case 22u: { // transform
+            let rotation: vec4<f32> = vec4(data[3], data[4], data[5], data[6]);
+            let scale: vec3<f32> = vec3(data[7], data[8], data[9]);
+            return quat_rotate(rotation, gradient / scale);
+        }
+        // repetitions only translate the point
+        default: { return gradient; }
+    }
+}
+
+/// Lipschitz bound of the domain operation around the point, to scale the distance of its subtree back.
+/// Twist and bend stretch space more and more away from their axis: a step of the distance
+/// can't go further than the distance away from the axis, so we take the stretch there.
+fn scope_stretch(at: vec3<f32>, sdf: f32, csg_index: u32) -> f32 {
+    let data: array<f32, 11> = csg_objects[csg_index].data;
+    let rate = data[0];
+    var axis_distance: f32 = 0.0;
+    switch u32(data[1]) {
+        case 1u: { axis_distance = length(at.xz); }
+        case 2u: { axis_distance = length(at.xy); }
+        // constant stretch
+        default: { return rate; }
+    }
+    // the jacobian is a rotation times the identity plus a shear of rate * distance to the axis
+    return 1.0 + rate * (axis_distance + abs(sdf));
+}
+
+/// Bring the point in the space of a primitive laid out as offset, then rotation quaternion.
+fn primitive_space(at: vec3<f32>, data: array<f32, 11>) -> vec3<f32> {
+    let position: vec3<f32> = vec3(data[0], data[1], data[2]);
+    let rotation: vec4<f32> = vec4(data[3], data[4], data[5], data[6]);
+    // the primitive is rotated by the quaternion, so bring the point back with the inverse rotation
+    return quat_rotate(quat_conjugate(rotation), at - position);
+}
+
 // utils
 
+/// Rotate the vector by the unit quaternion (x, y, z, w).
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    let t = 2.0 * cross(q.xyz, v);
+    return v + q.w * t + cross(q.xyz, t);
+}
+
+fn quat_conjugate(q: vec4<f32>) -> vec4<f32> {
+    return vec4(-q.xyz, q.w);
+}
+
+/// Polynomial smooth min, blending over k. The result is at most k / 4 under the hard min.
 fn smin(a: f32, b: f32, k: f32) -> f32 {
     let h: f32 = clamp(0.5 + 0.5*(a-b)/k, 0.0, 1.0);
     return mix(a, b, h) - k*h*(1.0-h);
+}
+
+fn smax(a: f32, b: f32, k: f32) -> f32 {
+    return -smin(-a, -b, k);
+}
+
+/// Smooth min of distances carrying their gradient in xyz.
+/// The blend term does not depend on h where it is not clamped, so the gradient is the blend of both.
+fn smin_gradient(a: vec4<f32>, b: vec4<f32>, k: f32) -> vec4<f32> {
+    let h: f32 = clamp(0.5 + 0.5*(a.w-b.w)/k, 0.0, 1.0);
+    return vec4(mix(a.xyz, b.xyz, h), mix(a.w, b.w, h) - k*h*(1.0-h));
+}
+
+fn smax_gradient(a: vec4<f32>, b: vec4<f32>, k: f32) -> vec4<f32> {
+    return -smin_gradient(-a, -b, k);
+}
+
+/// Normalize, but leave the zero vector alone instead of making it nan.
+fn safe_normalize(v: vec3<f32>) -> vec3<f32> {
+    let l = length(v);
+    return select(vec3(0.0), v / l, l > 0.0);
+}
+
+/// Blend radius of a smooth operation node, kept away from 0 so smin does not divide by it.
+fn blend_radius(csg_index: u32) -> f32 {
+    return max(csg_objects[csg_index].data[0], 0.000001);
 }
\ No newline at end of file
@@ -1,13 +1,21 @@
 pub mod components;
+pub mod entity;
 pub(crate) mod camera;
+mod hierarchy;
+mod transform_propagation;
 
-use self::{camera::Camera, components::{transform::Transform, csg_renderer::CsgRenderer}};
+use legion::IntoQuery;
+
+use crate::renderer::raymarch_settings::RaymarchSettings;
+
+use self::{camera::Camera, components::{transform::{Transform, GlobalTransform}, csg_renderer::CsgRenderer}, entity::Entity, transform_propagation::TransformPropagation};
 
 
 /// Representation of the world we are trying to render.
 pub struct World {
     world: legion::World,
     main_camera: Camera,
+    transform_propagation: TransformPropagation,
 }
 
 impl World {
@@ -15,6 +23,7 @@ impl World {
         World {
             world: legion::World::default(),
             main_camera,
+            transform_propagation: TransformPropagation::new(),
         }
     }
 
@@ -34,9 +43,123 @@ impl World {
         &mut self.main_camera
     }
 
-    pub(crate) fn add_obj(&mut self, transform: Transform, csg_asset_id: u64) {
+    /// Create an object that renders the csg asset with the given id.
+    pub fn spawn(&mut self, transform: Transform, csg_asset_id: u64) -> Entity {
         let renderer = CsgRenderer::new(csg_asset_id);
-        self.world.push((transform, renderer));
+        Entity(self.world.push((transform, renderer)))
+    }
+
+    /// Remove the object from the world, along with everything attached to it.
+    /// Their gpu resources are released on the next frame.
+    /// Returns false if the entity was already despawned.
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        hierarchy::despawn(&mut self.world, entity.0)
+    }
+
+    /// Attach the child to the parent, so its transform becomes relative to the one of the parent.
+    /// Returns false if one of the entities does not exist, or if the child is an ancestor of the parent.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) -> bool {
+        hierarchy::set_parent(&mut self.world, child.0, parent.0)
+    }
+
+    /// Detach the entity from its parent, its transform becomes relative to the world.
+    /// Returns false if the entity does not exist.
+    pub fn remove_parent(&mut self, entity: Entity) -> bool {
+        hierarchy::remove_parent(&mut self.world, entity.0)
+    }
+
+    pub fn parent(&self, entity: Entity) -> Option<Entity> {
+        hierarchy::parent_of(&self.world, entity.0).map(Entity)
+    }
+
+    pub fn children(&self, entity: Entity) -> Vec<Entity> {
+        hierarchy::children_of(&self.world, entity.0).into_iter().map(Entity).collect()
+    }
+
+    /// Matrix from the object space to the world space, with all the ancestors applied.
+    /// This is updated once per frame, so changes made since the last render are not in there yet.
+    pub fn global_matrix(&self, entity: Entity) -> Option<glam::Mat4> {
+        let entry = self.world.entry_ref(entity.0).ok()?;
+        let global_transform = entry.into_component::<GlobalTransform>().ok()?;
+        Some(global_transform.matrix())
+    }
+
+    pub(crate) fn propagate_transforms(&mut self) {
+        self.transform_propagation.propagate(&mut self.world);
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.world.contains(entity.0)
+    }
+
+    pub fn transform(&self, entity: Entity) -> Option<&Transform> {
+        self.world.entry_ref(entity.0).ok()?.into_component::<Transform>().ok()
     }
 
-}
\ No newline at end of file
+    /// Mutable access to the transform of the object.
+    /// This flags the transform as changed, so it is recomputed and uploaded on the next frame.
+    pub fn transform_mut(&mut self, entity: Entity) -> Option<&mut Transform> {
+        self.world.entry(entity.0)?.into_component_mut::<Transform>().ok()
+    }
+
+    /// Id of the csg asset the object renders.
+    pub fn asset(&self, entity: Entity) -> Option<u64> {
+        let entry = self.world.entry_ref(entity.0).ok()?;
+        let csg_renderer = entry.get_component::<CsgRenderer>().ok()?;
+        Some(csg_renderer.asset_id())
+    }
+
+    /// Change the csg asset the object renders.
+    /// Returns false if the entity does not exist or is not a csg object.
+    pub fn set_asset(&mut self, entity: Entity, csg_asset_id: u64) -> bool {
+        let Some(mut entry) = self.world.entry(entity.0) else {
+            return false;
+        };
+        match entry.get_component_mut::<CsgRenderer>() {
+            Ok(csg_renderer) => {
+                csg_renderer.set_asset_id(csg_asset_id);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Raymarching settings used for this object instead of the ones of the renderer, if any.
+    pub fn raymarch_settings(&self, entity: Entity) -> Option<RaymarchSettings> {
+        let entry = self.world.entry_ref(entity.0).ok()?;
+        entry.get_component::<CsgRenderer>().ok()?.raymarch_settings()
+    }
+
+    /// Override the raymarching settings of the renderer for this object, or go back to them with None.
+    /// Returns false if the entity does not exist or is not a csg object.
+    pub fn set_raymarch_settings(&mut self, entity: Entity, raymarch_settings: Option<RaymarchSettings>) -> bool {
+        let Some(mut entry) = self.world.entry(entity.0) else {
+            return false;
+        };
+        match entry.get_component_mut::<CsgRenderer>() {
+            Ok(csg_renderer) => {
+                csg_renderer.set_raymarch_settings(raymarch_settings);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+
+    /// Call f on every object of the world, with its transform and asset id.
+    pub fn for_each_obj<F>(&self, mut f: F) where F: FnMut(Entity, &Transform, u64) {
+        let mut query = <(legion::Entity, &Transform, &CsgRenderer)>::query();
+        for (entity, transform, csg_renderer) in query.iter(&self.world) {
+            f(Entity(*entity), transform, csg_renderer.asset_id());
+        }
+    }
+
+    /// Call f on every object of the world, with mutable access to its transform.
+    /// All the transforms are flagged as changed, prefer transform_mut when only a few are modified.
+    pub fn for_each_obj_mut<F>(&mut self, mut f: F) where F: FnMut(Entity, &mut Transform, u64) {
+        let mut query = <(legion::Entity, &mut Transform, &CsgRenderer)>::query();
+        for (entity, transform, csg_renderer) in query.iter_mut(&mut self.world) {
+            f(Entity(*entity), transform, csg_renderer.asset_id());
+        }
+    }
+
+}
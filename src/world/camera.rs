@@ -66,7 +66,7 @@ impl BufferElem for CameraToGpu {
     const VISIBILITY: wgpu::ShaderStages = wgpu::ShaderStages::VERTEX_FRAGMENT;
     const SIZE: u64 = CAMERA_GPU_SIZE as u64;
     fn to_bytes(&self) -> &[u8] {
-        bytemuck::cast_ref::<CameraToGpu, [u8; CAMERA_GPU_SIZE]>(&self)
+        bytemuck::cast_ref::<CameraToGpu, [u8; CAMERA_GPU_SIZE]>(self)
     }
 }
 
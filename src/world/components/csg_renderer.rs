@@ -1,14 +1,17 @@
 
+use crate::renderer::raymarch_settings::RaymarchSettings;
+
 pub(crate) struct CsgRenderer {
-    _bounding_box_size: f32,
     csg_asset_id: u64,
+    /// Overrides the settings of the renderer for this object.
+    raymarch_settings: Option<RaymarchSettings>,
 }
 
 impl CsgRenderer {
     pub fn new(asset_id: u64) -> CsgRenderer {
         CsgRenderer {
-            _bounding_box_size: 1., // todo: compute
             csg_asset_id: asset_id,
+            raymarch_settings: None,
         }
     }
 
@@ -16,5 +19,17 @@ impl CsgRenderer {
         self.csg_asset_id
     } 
 
+    pub(crate) fn set_asset_id(&mut self, asset_id: u64) {
+        self.csg_asset_id = asset_id;
+    }
+
+    pub(crate) fn raymarch_settings(&self) -> Option<RaymarchSettings> {
+        self.raymarch_settings
+    }
+
+    pub(crate) fn set_raymarch_settings(&mut self, raymarch_settings: Option<RaymarchSettings>) {
+        self.raymarch_settings = raymarch_settings;
+    }
+
 }
 
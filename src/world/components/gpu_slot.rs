@@ -0,0 +1,15 @@
+
+/// Index of the entity transform in the gpu transform buffer.
+/// This is given by the renderer to every entity with a csg renderer, and stays the same until it is despawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct GpuSlot(u32);
+
+impl GpuSlot {
+    pub(crate) fn new(index: u32) -> GpuSlot {
+        GpuSlot(index)
+    }
+
+    pub(crate) fn index(&self) -> u32 {
+        self.0
+    }
+}
@@ -0,0 +1,9 @@
+
+
+/// The entity this one is attached to. Its transform is relative to the parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Parent(pub(crate) legion::Entity);
+
+/// Entities attached to this one. Kept in sync with the parent component of each child.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Children(pub(crate) Vec<legion::Entity>);
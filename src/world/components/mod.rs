@@ -1,3 +1,5 @@
 pub(crate) mod csg_renderer;
+pub(crate) mod gpu_slot;
+pub(crate) mod hierarchy;
 pub mod transform;
 pub mod light;
\ No newline at end of file
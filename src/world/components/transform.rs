@@ -1,10 +1,12 @@
-use crate::renderer::buffer::BufferElem;
 
+/// Position, rotation and scale of an entity, relative to its parent if it has one.
+/// Modifications are picked up by legion change detection, so only the transforms
+/// that were accessed mutably since the last frame are recomputed and sent to the gpu.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Transform {
     position: glam::Vec3,
     rotation: glam::Quat,
     scale: glam::Vec3,
-    dirty: bool,
 }
 
 impl Transform {
@@ -13,14 +15,22 @@ impl Transform {
             position: glam::Vec3::ZERO,
             rotation: glam::Quat::IDENTITY,
             scale: glam::Vec3::ONE,
-            dirty: true,
+        }
+    }
+
+    /// Decompose an affine matrix. Shear can't be represented and is lost.
+    pub fn from_matrix(matrix: glam::Mat4) -> Transform {
+        let (scale, rotation, position) = matrix.to_scale_rotation_translation();
+        Transform {
+            position,
+            rotation,
+            scale,
         }
     }
 
     pub fn at(self, at: glam::Vec3) -> Transform {
         Transform {
             position: at,
-            dirty: true,
             ..self
         }
     }
@@ -28,66 +38,323 @@ impl Transform {
     pub fn rotated(self, rotation: glam::Quat) -> Transform {
         Transform { 
             rotation,
-            dirty: true,
             ..self
         }
     }
 
-    pub(crate) fn is_dirty(&self) -> bool {
-        self.dirty
+    pub fn scaled(self, scale: glam::Vec3) -> Transform {
+        Transform {
+            scale,
+            ..self
+        }
+    }
+
+    pub fn position(&self) -> glam::Vec3 {
+        self.position
+    }
+
+    pub fn rotation(&self) -> glam::Quat {
+        self.rotation
+    }
+
+    pub fn scale(&self) -> glam::Vec3 {
+        self.scale
+    }
+
+    pub fn set_position(&mut self, position: glam::Vec3) {
+        self.position = position;
+    }
+
+    pub fn translate(&mut self, delta: glam::Vec3) {
+        self.position += delta;
+    }
+
+    pub fn set_rotation(&mut self, rotation: glam::Quat) {
+        self.rotation = rotation;
+    }
+
+    /// Apply the rotation on top of the current one.
+    pub fn rotate(&mut self, rotation: glam::Quat) {
+        self.rotation = (rotation * self.rotation).normalize();
+    }
+
+    pub fn set_scale(&mut self, scale: glam::Vec3) {
+        self.scale = scale;
     }
 
-    pub(crate) fn set_clean(&mut self) {
-        self.dirty = false;
+    /// Rotate so that the forward axis (-z, as for the camera) points toward the target,
+    /// with the up axis as close as possible to the given one.
+    /// Nothing happens if the target is at the position, or in the up direction.
+    pub fn look_at(&mut self, target: glam::Vec3, up: glam::Vec3) {
+        let Some(forward) = (target - self.position).try_normalize() else {
+            return;
+        };
+        let Some(right) = forward.cross(up).try_normalize() else {
+            return;
+        };
+        let up = right.cross(forward);
+        self.rotation = glam::Quat::from_mat3(&glam::Mat3::from_cols(right, up, -forward));
     }
 
-    pub(crate) fn recompute_matrix(&self) -> TransformToGpu {
-        TransformToGpu::new(
-            glam::Mat4::from_scale_rotation_translation(
-                self.scale,
-                self.rotation,
-                self.position
-            )
+    /// Interpolate between two transforms. Position and scale are linearly interpolated,
+    /// and the rotation is spherically interpolated so it turns at constant speed.
+    pub fn lerp(&self, other: &Transform, t: f32) -> Transform {
+        Transform {
+            position: self.position.lerp(other.position, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+
+    /// Matrix of the transform, relative to the parent if there is one.
+    pub(crate) fn local_matrix(&self) -> glam::Mat4 {
+        glam::Mat4::from_scale_rotation_translation(
+            self.scale,
+            self.rotation,
+            self.position
         )
     }
 
 }
 
+/// Transform of an entity relative to the world, with all its ancestors applied.
+/// This is computed from the local transforms before each frame, and is what gets sent to the gpu.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct GlobalTransform {
+    matrix: glam::Mat4,
+}
+
+impl GlobalTransform {
+    pub(crate) fn new(matrix: glam::Mat4) -> GlobalTransform {
+        GlobalTransform {
+            matrix,
+        }
+    }
+
+    pub(crate) fn matrix(&self) -> glam::Mat4 {
+        self.matrix
+    }
+
+    pub(crate) fn set_matrix(&mut self, matrix: glam::Mat4) {
+        self.matrix = matrix;
+    }
+
+    pub(crate) fn to_gpu(self) -> TransformToGpu {
+        TransformToGpu::new(self.matrix)
+    }
+}
+
 
+#[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct TransformToGpu {
-    #[allow(unused)]
     model_mat: glam::Mat4,
-    #[allow(unused)]
     inv_model: glam::Mat4,
+    /// Lower bound of how much the model matrix scales lengths.
+    /// The raymarcher scales object space distances by it, so steps never overshoot in world space.
+    min_scale: f32,
+    _padding: [f32; 3],
 }
 
 unsafe impl bytemuck::Zeroable for TransformToGpu {}
 unsafe impl bytemuck::Pod for TransformToGpu {}
 
-const TRANSFORM_SIZE: usize = std::mem::size_of::<TransformToGpu>();
-
-impl BufferElem for TransformToGpu {
-    const BINDING: u32 = 0;
-    const BINDING_TYPE: wgpu::BindingType = wgpu::BindingType::Buffer {
-        ty: wgpu::BufferBindingType::Uniform,
-        has_dynamic_offset: true,
-        min_binding_size: None,
-    };
-    #[cfg(debug_assertions)]
-    const LABEL: &'static str = "transform";
-    const VISIBILITY: wgpu::ShaderStages = wgpu::ShaderStages::VERTEX_FRAGMENT;
-    const SIZE: u64 = TRANSFORM_SIZE as u64;
-    fn to_bytes(&self) -> &[u8] {
-        bytemuck::cast_ref::<TransformToGpu, [u8; TRANSFORM_SIZE]>(self)
-    }
-}
-
 impl TransformToGpu {
     pub(crate) fn new(model_mat: glam::Mat4) -> TransformToGpu {
         TransformToGpu {
             model_mat,
-            inv_model: model_mat.inverse()
+            inv_model: model_mat.inverse(),
+            min_scale: min_scale(glam::Mat3::from_mat4(model_mat)),
+            _padding: [0.0; 3],
+        }
+    }
+}
+
+/// Smallest factor the matrix can scale a length by, which is its smallest singular value.
+/// For a simple scale this is the smallest axis, but parents with non uniform scale can add shear.
+fn min_scale(matrix: glam::Mat3) -> f32 {
+    // singular values are the square roots of the eigenvalues of mt * m
+    let a = matrix.transpose() * matrix;
+
+    // closed form for the eigenvalues of a symmetric 3x3 matrix
+    let off_diagonal = a.x_axis.y.powi(2) + a.x_axis.z.powi(2) + a.y_axis.z.powi(2);
+    let diagonal = glam::Vec3::new(a.x_axis.x, a.y_axis.y, a.z_axis.z);
+    if off_diagonal == 0.0 {
+        return diagonal.min_element().max(0.0).sqrt();
+    }
+    let q = (diagonal.x + diagonal.y + diagonal.z) / 3.0;
+    let p = (((diagonal - q).length_squared() + 2.0 * off_diagonal) / 6.0).sqrt();
+    let b = (a - glam::Mat3::from_diagonal(glam::Vec3::splat(q))) * (1.0 / p);
+    let phi = (b.determinant() / 2.0).clamp(-1.0, 1.0).acos() / 3.0;
+    let smallest_eigenvalue = q + 2.0 * p * (phi + 2.0 * std::f32::consts::FRAC_PI_3).cos();
+
+    smallest_eigenvalue.max(0.0).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_vec_eq(a: glam::Vec3, b: glam::Vec3) {
+        assert!(a.abs_diff_eq(b, 1.0e-5), "{a} != {b}");
+    }
+
+    /// Quaternions q and -q are the same rotation.
+    fn assert_rotation_eq(a: glam::Quat, b: glam::Quat) {
+        assert!(a.dot(b).abs() > 1.0 - 1.0e-5, "{a} != {b}");
+    }
+
+    #[test]
+    fn look_at_points_forward_to_the_target() {
+        let mut transform = Transform::origin().at(glam::Vec3::new(1.0, 2.0, 3.0));
+        let target = glam::Vec3::new(-2.0, 0.5, 1.0);
+        transform.look_at(target, glam::Vec3::Y);
+
+        let forward = transform.rotation() * glam::Vec3::NEG_Z;
+        assert_vec_eq(forward, (target - transform.position()).normalize());
+        // up stays in the plane of the forward axis and the requested up
+        let up = transform.rotation() * glam::Vec3::Y;
+        assert!(up.dot(glam::Vec3::Y) > 0.0);
+        assert!(up.dot(forward.cross(glam::Vec3::Y)).abs() < 1.0e-5);
+        assert!(transform.rotation().is_normalized());
+    }
+
+    #[test]
+    fn look_at_default_direction_is_identity() {
+        let mut transform = Transform::origin();
+        transform.look_at(glam::Vec3::new(0.0, 0.0, -5.0), glam::Vec3::Y);
+        assert_rotation_eq(transform.rotation(), glam::Quat::IDENTITY);
+    }
+
+    #[test]
+    fn look_at_degenerate_targets_keep_the_rotation() {
+        let rotation = glam::Quat::from_rotation_x(0.4);
+        let mut transform = Transform::origin().at(glam::Vec3::ONE).rotated(rotation);
+
+        transform.look_at(glam::Vec3::ONE, glam::Vec3::Y);
+        assert_eq!(transform.rotation(), rotation);
+
+        transform.look_at(glam::Vec3::new(1.0, 4.0, 1.0), glam::Vec3::Y);
+        assert_eq!(transform.rotation(), rotation);
+    }
+
+    #[test]
+    fn from_matrix_round_trips() {
+        let transform = Transform::origin()
+            .at(glam::Vec3::new(1.0, -2.0, 0.5))
+            .rotated(glam::Quat::from_euler(glam::EulerRot::YXZ, 0.3, -1.1, 2.0))
+            .scaled(glam::Vec3::new(2.0, 0.5, 1.5));
+
+        let decomposed = Transform::from_matrix(transform.local_matrix());
+
+        assert_vec_eq(decomposed.position(), transform.position());
+        assert_rotation_eq(decomposed.rotation(), transform.rotation());
+        assert_vec_eq(decomposed.scale(), transform.scale());
+        assert!(decomposed.local_matrix().abs_diff_eq(transform.local_matrix(), 1.0e-5));
+    }
+
+    #[test]
+    fn lerp_endpoints_are_the_transforms() {
+        let from = Transform::origin()
+            .at(glam::Vec3::new(1.0, 0.0, 0.0))
+            .rotated(glam::Quat::from_rotation_y(0.2))
+            .scaled(glam::Vec3::splat(1.0));
+        let to = Transform::origin()
+            .at(glam::Vec3::new(-3.0, 2.0, 4.0))
+            .rotated(glam::Quat::from_rotation_z(2.5))
+            .scaled(glam::Vec3::new(3.0, 2.0, 0.5));
+
+        let start = from.lerp(&to, 0.0);
+        assert_vec_eq(start.position(), from.position());
+        assert_rotation_eq(start.rotation(), from.rotation());
+        assert_vec_eq(start.scale(), from.scale());
+
+        let end = from.lerp(&to, 1.0);
+        assert_vec_eq(end.position(), to.position());
+        assert_rotation_eq(end.rotation(), to.rotation());
+        assert_vec_eq(end.scale(), to.scale());
+    }
+
+    #[test]
+    fn lerp_turns_at_constant_speed() {
+        let from = Transform::origin();
+        let to = Transform::origin().at(glam::Vec3::new(4.0, 0.0, 0.0)).rotated(glam::Quat::from_rotation_y(1.6));
+
+        for t in [0.25, 0.5, 0.75] {
+            let between = from.lerp(&to, t);
+            assert_vec_eq(between.position(), glam::Vec3::new(4.0 * t, 0.0, 0.0));
+            assert_rotation_eq(between.rotation(), glam::Quat::from_rotation_y(1.6 * t));
+        }
+    }
+
+    #[test]
+    fn lerp_takes_the_shortest_path() {
+        // the same rotation as the target, with the opposite sign
+        let from = Transform::origin();
+        let to = Transform::origin().rotated(-glam::Quat::from_rotation_y(0.5));
+
+        let halfway = from.lerp(&to, 0.5);
+        assert_rotation_eq(halfway.rotation(), glam::Quat::from_rotation_y(0.25));
+    }
+
+    /// Smallest singular value by power iteration on the inverse, to check the closed form against.
+    fn min_scale_by_iteration(matrix: glam::Mat3) -> f32 {
+        let inverse = matrix.inverse();
+        let a = inverse.transpose() * inverse;
+        let mut v = glam::Vec3::new(1.0, 0.7, 0.3).normalize();
+        for _ in 0..200 {
+            v = (a * v).normalize();
+        }
+        1.0 / (inverse * v).length()
+    }
+
+    fn assert_min_scale(matrix: glam::Mat3, expected: f32) {
+        let min_scale = min_scale(matrix);
+        assert!((min_scale - expected).abs() <= 1.0e-4 * expected.max(1.0), "{min_scale} != {expected}");
+        // no direction is scaled by less than the bound
+        for i in 0..64 {
+            let (theta, phi) = (i as f32 * 0.77, i as f32 * 0.31);
+            let dir = glam::Vec3::new(theta.cos() * phi.sin(), theta.sin() * phi.sin(), phi.cos());
+            assert!((matrix * dir).length() >= min_scale * (1.0 - 1.0e-4), "{dir} is scaled below {min_scale}");
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn min_scale_of_a_non_uniform_scale_is_its_smallest_axis() {
+        assert_min_scale(glam::Mat3::from_diagonal(glam::Vec3::new(2.0, 0.5, 3.0)), 0.5);
+    }
+
+    #[test]
+    fn min_scale_ignores_rotations() {
+        let scale = glam::Mat3::from_diagonal(glam::Vec3::new(2.0, 0.5, 3.0));
+        let before = glam::Mat3::from_quat(glam::Quat::from_euler(glam::EulerRot::YXZ, 0.3, -1.1, 2.0));
+        let after = glam::Mat3::from_quat(glam::Quat::from_euler(glam::EulerRot::XYZ, 1.4, 0.2, -0.6));
+        assert_min_scale(after * scale * before, 0.5);
+    }
+
+    #[test]
+    fn min_scale_of_a_sheared_parent_child_product() {
+        // a non uniform parent scale on a rotated child adds shear, no axis gives the smallest scale
+        let parent = Transform::origin().scaled(glam::Vec3::new(1.0, 3.0, 1.0));
+        let child = Transform::origin()
+            .rotated(glam::Quat::from_rotation_z(0.7))
+            .scaled(glam::Vec3::new(0.5, 1.0, 1.5));
+        let matrix = glam::Mat3::from_mat4(parent.local_matrix() * child.local_matrix());
+
+        let expected = min_scale_by_iteration(matrix);
+        // the shortest scaled axis is not the answer anymore
+        let shortest_axis = matrix.x_axis.length().min(matrix.y_axis.length()).min(matrix.z_axis.length());
+        assert!(expected < 0.99 * shortest_axis, "{expected} is the shortest axis, there is no shear to test");
+        assert_min_scale(matrix, expected);
+    }
+
+    #[test]
+    fn min_scale_of_a_near_degenerate_scale() {
+        let rotation = glam::Mat3::from_quat(glam::Quat::from_rotation_y(0.9));
+        let flat = rotation * glam::Mat3::from_diagonal(glam::Vec3::new(1.0, 1.0e-3, 2.0));
+        assert_min_scale(flat, 1.0e-3);
+
+        let degenerate = rotation * glam::Mat3::from_diagonal(glam::Vec3::new(1.0, 0.0, 2.0));
+        assert_eq!(min_scale(degenerate), 0.0);
+    }
+}
@@ -0,0 +1,6 @@
+
+
+/// Handle to an object of the world, returned when creating it.
+/// It stays valid until the object is despawned, and is never reused for another object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Entity(pub(crate) legion::Entity);
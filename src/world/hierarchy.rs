@@ -0,0 +1,165 @@
+use super::components::hierarchy::{Parent, Children};
+
+
+/// Remove the entity from the world, along with all its descendants.
+/// Returns false if the entity was already despawned.
+pub(crate) fn despawn(world: &mut legion::World, entity: legion::Entity) -> bool {
+    if !world.contains(entity) {
+        return false;
+    }
+    detach(world, entity);
+
+    let mut to_remove = vec![entity];
+    while let Some(entity) = to_remove.pop() {
+        to_remove.extend(children_of(world, entity));
+        world.remove(entity);
+    }
+    true
+}
+
+/// Attach the child to the parent, on both sides.
+/// Returns false if one of the entities does not exist, or if the child is an ancestor of the parent.
+pub(crate) fn set_parent(world: &mut legion::World, child: legion::Entity, parent: legion::Entity) -> bool {
+    if !world.contains(child) || !world.contains(parent) {
+        return false;
+    }
+    // we can't have cycles in the hierarchy
+    let mut ancestor = Some(parent);
+    while let Some(entity) = ancestor {
+        if entity == child {
+            return false;
+        }
+        ancestor = parent_of(world, entity);
+    }
+
+    detach(world, child);
+
+    if let Some(mut entry) = world.entry(parent) {
+        match entry.get_component_mut::<Children>() {
+            Ok(children) => children.0.push(child),
+            Err(_) => entry.add_component(Children(vec![child])),
+        }
+    }
+    if let Some(mut entry) = world.entry(child) {
+        // this moves the entity to another archetype, which flags its transform as changed
+        entry.add_component(Parent(parent));
+    }
+    true
+}
+
+/// Returns false if the entity does not exist.
+pub(crate) fn remove_parent(world: &mut legion::World, entity: legion::Entity) -> bool {
+    if !world.contains(entity) {
+        return false;
+    }
+    detach(world, entity);
+    true
+}
+
+pub(crate) fn parent_of(world: &legion::World, entity: legion::Entity) -> Option<legion::Entity> {
+    let entry = world.entry_ref(entity).ok()?;
+    let parent = entry.into_component::<Parent>().ok()?;
+    Some(parent.0)
+}
+
+pub(crate) fn children_of(world: &legion::World, entity: legion::Entity) -> Vec<legion::Entity> {
+    let Ok(entry) = world.entry_ref(entity) else {
+        return Vec::new();
+    };
+    match entry.into_component::<Children>() {
+        Ok(children) => children.0.clone(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Remove the link between the entity and its parent, on both sides.
+fn detach(world: &mut legion::World, entity: legion::Entity) {
+    let Some(parent) = parent_of(world, entity) else {
+        return;
+    };
+    if let Some(mut entry) = world.entry(parent) {
+        if let Ok(children) = entry.get_component_mut::<Children>() {
+            children.0.retain(|child| *child != entity);
+        }
+    }
+    if let Some(mut entry) = world.entry(entity) {
+        entry.remove_component::<Parent>();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::world::components::transform::Transform;
+
+    use super::*;
+
+    fn spawn(world: &mut legion::World) -> legion::Entity {
+        world.push((Transform::origin(),))
+    }
+
+    #[test]
+    fn set_parent_links_both_sides() {
+        let mut world = legion::World::default();
+        let parent = spawn(&mut world);
+        let child = spawn(&mut world);
+
+        assert!(set_parent(&mut world, child, parent));
+        assert_eq!(parent_of(&world, child), Some(parent));
+        assert_eq!(children_of(&world, parent), vec![child]);
+    }
+
+    #[test]
+    fn set_parent_refuses_cycles() {
+        let mut world = legion::World::default();
+        let root = spawn(&mut world);
+        let child = spawn(&mut world);
+        let grandchild = spawn(&mut world);
+        assert!(set_parent(&mut world, child, root));
+        assert!(set_parent(&mut world, grandchild, child));
+
+        assert!(!set_parent(&mut world, root, grandchild));
+        assert!(!set_parent(&mut world, child, grandchild));
+        assert!(!set_parent(&mut world, root, root));
+
+        // the hierarchy is left untouched
+        assert_eq!(parent_of(&world, root), None);
+        assert_eq!(parent_of(&world, child), Some(root));
+        assert_eq!(children_of(&world, grandchild), Vec::new());
+    }
+
+    #[test]
+    fn reparenting_moves_the_child() {
+        let mut world = legion::World::default();
+        let first = spawn(&mut world);
+        let second = spawn(&mut world);
+        let child = spawn(&mut world);
+        assert!(set_parent(&mut world, child, first));
+        assert!(set_parent(&mut world, child, second));
+
+        assert_eq!(parent_of(&world, child), Some(second));
+        assert_eq!(children_of(&world, first), Vec::new());
+        assert_eq!(children_of(&world, second), vec![child]);
+    }
+
+    #[test]
+    fn despawn_removes_the_subtree() {
+        let mut world = legion::World::default();
+        let root = spawn(&mut world);
+        let child = spawn(&mut world);
+        let grandchild = spawn(&mut world);
+        let sibling = spawn(&mut world);
+        assert!(set_parent(&mut world, child, root));
+        assert!(set_parent(&mut world, grandchild, child));
+        assert!(set_parent(&mut world, sibling, root));
+
+        assert!(despawn(&mut world, child));
+
+        assert!(!world.contains(child));
+        assert!(!world.contains(grandchild));
+        assert!(world.contains(root));
+        assert!(world.contains(sibling));
+        assert_eq!(children_of(&world, root), vec![sibling]);
+        assert!(!despawn(&mut world, child));
+    }
+}
@@ -0,0 +1,148 @@
+use legion::IntoQuery;
+
+use super::components::{transform::{Transform, GlobalTransform}, hierarchy::{Parent, Children}};
+
+
+/// Query of the entities with a changed transform, boxed so the type of its filter does not have to be named.
+type ChangedTransformsQuery = Box<dyn FnMut(&legion::World) -> std::collections::HashSet<legion::Entity>>;
+
+/// Computes the global transform of every entity from its local transform and the ones of its ancestors.
+pub(crate) struct TransformPropagation {
+    /// Entities whose transform may have changed since the last propagation.
+    /// Legion tracks changes per query, so it has to live across frames.
+    changed_transforms: ChangedTransformsQuery,
+}
+
+impl TransformPropagation {
+    pub(crate) fn new() -> TransformPropagation {
+        let mut query = <legion::Entity>::query()
+            .filter(legion::component::<Transform>() & legion::maybe_changed::<Transform>());
+        TransformPropagation {
+            changed_transforms: Box::new(move |world| query.iter(world).copied().collect()),
+        }
+    }
+
+    /// Only the subtrees under a modified transform are recomputed.
+    /// Change detection is coarse, so some untouched transforms may be recomputed as well.
+    pub(crate) fn propagate(&mut self, world: &mut legion::World) {
+        // new entities get a global transform, moving them into a new archetype flags their transform as changed
+        let mut query = <legion::Entity>::query()
+            .filter(legion::component::<Transform>() & !legion::component::<GlobalTransform>());
+        let new_entities: Vec<legion::Entity> = query.iter(world).copied().collect();
+        for entity in new_entities.into_iter() {
+            if let Some(mut entry) = world.entry(entity) {
+                entry.add_component(GlobalTransform::new(glam::Mat4::IDENTITY));
+            }
+        }
+
+        let changed = (self.changed_transforms)(world);
+        if changed.is_empty() {
+            return;
+        }
+
+        let mut query = <legion::Entity>::query()
+            .filter(legion::component::<Transform>() & !legion::component::<Parent>());
+        let roots: Vec<legion::Entity> = query.iter(world).copied().collect();
+
+        // depth first walk from the roots, with the global matrix of the parent and whether it changed
+        let mut stack: Vec<(legion::Entity, glam::Mat4, bool)> = roots.into_iter()
+            .map(|root| (root, glam::Mat4::IDENTITY, false))
+            .collect();
+
+        while let Some((entity, parent_matrix, parent_changed)) = stack.pop() {
+            let is_changed = parent_changed || changed.contains(&entity);
+
+            let Ok(entry) = world.entry_ref(entity) else {
+                continue;
+            };
+            let (Ok(transform), Ok(global_transform)) = (entry.get_component::<Transform>(), entry.get_component::<GlobalTransform>()) else {
+                // no transform, nothing to place here or below
+                continue;
+            };
+            let matrix = match is_changed {
+                true => parent_matrix * transform.local_matrix(),
+                false => global_transform.matrix(),
+            };
+            if let Ok(children) = entry.get_component::<Children>() {
+                stack.extend(children.0.iter().map(|child| (*child, matrix, is_changed)));
+            }
+
+            // only write the ones that changed, writing flags them for upload
+            if is_changed {
+                if let Some(mut entry) = world.entry(entity) {
+                    if let Ok(global_transform) = entry.get_component_mut::<GlobalTransform>() {
+                        global_transform.set_matrix(matrix);
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::world::hierarchy::set_parent;
+
+    use super::*;
+
+    fn global_matrix(world: &legion::World, entity: legion::Entity) -> glam::Mat4 {
+        world.entry_ref(entity).unwrap().get_component::<GlobalTransform>().unwrap().matrix()
+    }
+
+    fn placed(position: glam::Vec3, angle: f32, scale: f32) -> Transform {
+        Transform::origin()
+            .at(position)
+            .rotated(glam::Quat::from_axis_angle(glam::Vec3::new(1.0, 2.0, 0.5).normalize(), angle))
+            .scaled(glam::Vec3::new(scale, 1.0, 0.5))
+    }
+
+    #[test]
+    fn roots_global_is_their_local() {
+        let mut world = legion::World::default();
+        let transform = placed(glam::Vec3::new(1.0, 2.0, 3.0), 0.7, 2.0);
+        let root = world.push((transform,));
+
+        TransformPropagation::new().propagate(&mut world);
+
+        assert!(global_matrix(&world, root).abs_diff_eq(transform.local_matrix(), 1.0e-5));
+    }
+
+    #[test]
+    fn child_global_is_parent_global_times_local() {
+        let mut world = legion::World::default();
+        let parent_transform = placed(glam::Vec3::new(1.0, 2.0, 3.0), 0.7, 2.0);
+        let child_transform = placed(glam::Vec3::new(0.0, -1.0, 0.5), -1.2, 0.3);
+        let parent = world.push((parent_transform,));
+        let child = world.push((child_transform,));
+        assert!(set_parent(&mut world, child, parent));
+
+        TransformPropagation::new().propagate(&mut world);
+
+        let expected = parent_transform.local_matrix() * child_transform.local_matrix();
+        assert!(global_matrix(&world, child).abs_diff_eq(expected, 1.0e-5));
+    }
+
+    #[test]
+    fn parent_change_reaches_grandchildren() {
+        let mut world = legion::World::default();
+        let root = world.push((placed(glam::Vec3::new(1.0, 0.0, 0.0), 0.3, 1.0),));
+        let child_transform = placed(glam::Vec3::new(0.0, 1.0, 0.0), 0.5, 2.0);
+        let grandchild_transform = placed(glam::Vec3::new(0.0, 0.0, 1.0), -0.4, 0.5);
+        let child = world.push((child_transform,));
+        let grandchild = world.push((grandchild_transform,));
+        assert!(set_parent(&mut world, child, root));
+        assert!(set_parent(&mut world, grandchild, child));
+
+        let mut propagation = TransformPropagation::new();
+        propagation.propagate(&mut world);
+
+        // only the root is modified
+        let moved_root = placed(glam::Vec3::new(-2.0, 3.0, 1.0), 1.1, 3.0);
+        *world.entry(root).unwrap().get_component_mut::<Transform>().unwrap() = moved_root;
+        propagation.propagate(&mut world);
+
+        let expected = moved_root.local_matrix() * child_transform.local_matrix() * grandchild_transform.local_matrix();
+        assert!(global_matrix(&world, grandchild).abs_diff_eq(expected, 1.0e-4));
+    }
+}